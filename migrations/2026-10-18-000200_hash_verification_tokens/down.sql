@@ -0,0 +1 @@
+-- Deleted plaintext tokens cannot be restored.
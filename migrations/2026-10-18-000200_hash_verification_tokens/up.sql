@@ -0,0 +1,2 @@
+-- Tokens are now stored hashed, so any plaintext tokens still pending can no longer be verified.
+DELETE FROM pending_email_verifications;
@@ -0,0 +1,3 @@
+ALTER TABLE pending_email_verifications
+    DROP COLUMN failed_attempts,
+    DROP COLUMN code_hash;
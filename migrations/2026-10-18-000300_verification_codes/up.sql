@@ -0,0 +1,3 @@
+ALTER TABLE pending_email_verifications
+    ADD COLUMN code_hash VARCHAR(128),
+    ADD COLUMN failed_attempts INT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+DELETE FROM pending_email_verifications WHERE purpose <> 'verify';
+ALTER TABLE pending_email_verifications DROP COLUMN purpose;
@@ -0,0 +1,2 @@
+ALTER TABLE pending_email_verifications
+    ADD COLUMN purpose VARCHAR(16) NOT NULL DEFAULT 'verify';
@@ -0,0 +1,7 @@
+DROP INDEX one_time_tokens_email_purpose_idx;
+DELETE FROM one_time_tokens WHERE purpose NOT IN ('verify', 'login');
+ALTER TABLE one_time_tokens
+    DROP COLUMN payload,
+    DROP COLUMN user_id;
+ALTER INDEX one_time_tokens_pkey RENAME TO pending_email_verifications_pkey;
+ALTER TABLE one_time_tokens RENAME TO pending_email_verifications;
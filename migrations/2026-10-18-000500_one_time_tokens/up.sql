@@ -0,0 +1,7 @@
+ALTER TABLE pending_email_verifications RENAME TO one_time_tokens;
+ALTER INDEX pending_email_verifications_pkey RENAME TO one_time_tokens_pkey;
+ALTER TABLE one_time_tokens
+    ADD COLUMN user_id UUID,
+    ADD COLUMN payload JSONB;
+
+CREATE INDEX one_time_tokens_email_purpose_idx ON one_time_tokens (email, purpose);
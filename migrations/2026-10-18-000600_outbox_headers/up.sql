@@ -0,0 +1 @@
+ALTER TABLE email_outbox ADD COLUMN headers JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,2 @@
+ALTER TABLE email_outbox DROP COLUMN transactional;
+DROP TABLE email_suppressions;
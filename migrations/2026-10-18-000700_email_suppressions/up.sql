@@ -0,0 +1,8 @@
+CREATE TABLE email_suppressions (
+    email VARCHAR(255) PRIMARY KEY,
+    reason VARCHAR(16) NOT NULL,
+    detail TEXT,
+    created TIMESTAMP NOT NULL
+);
+
+ALTER TABLE email_outbox ADD COLUMN transactional BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,4 @@
+DROP INDEX email_outbox_cancel_key_idx;
+
+ALTER TABLE email_outbox DROP COLUMN cancel_key;
+ALTER TABLE email_outbox DROP COLUMN send_at;
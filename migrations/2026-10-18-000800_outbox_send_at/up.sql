@@ -0,0 +1,4 @@
+ALTER TABLE email_outbox ADD COLUMN send_at TIMESTAMP;
+ALTER TABLE email_outbox ADD COLUMN cancel_key VARCHAR(255);
+
+CREATE INDEX email_outbox_cancel_key_idx ON email_outbox (cancel_key) WHERE status = 'pending';
@@ -0,0 +1 @@
+ALTER TABLE email_outbox ADD COLUMN digest BOOLEAN NOT NULL DEFAULT false;
@@ -1,27 +1,62 @@
 use crate::{
-    email::{EmailTemplate, EmailTemplateBuilder},
-    tables::{EmailVerification, UnverifiedEmailTable, UserAccountType, UserIdTable, UserTable},
+    email::{
+        locale::{recipient_locale, VERIFY_EMAIL},
+        EmailTemplate, EmailTemplateBuilder, VerificationMode, VerificationThrottle,
+    },
+    tables::{
+        EmailVerification, TokenPurpose, UnverifiedEmailTable, UserAccountType, UserId,
+        UserIdTable, UserTable, MIN_CODE_DIGITS,
+    },
 };
 use axum::{
-    extract::{Query, State},
-    http::{header, StatusCode},
+    extract::{Extension, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::{post, put},
     Json, Router,
 };
+use diesel_async::AsyncPgConnection;
 use email_address::EmailAddress;
 use serde::Deserialize;
 use serde_json::json;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use super::{super::AuthenticatedUser, AnyhowError, AppState, RejectReason};
-use crate::email::send_verification_email;
+use super::{super::AuthenticatedUser, accept_language, AnyhowError, AppState, RejectReason};
+use crate::email::{send_verification_code, send_verification_email};
 
 #[derive(Deserialize)]
 struct VerifyQuery {
     id: String,
 }
 
+#[derive(Deserialize)]
+struct ResendQuery {
+    #[serde(default)]
+    mode: VerificationMode,
+}
+
+#[derive(Deserialize)]
+struct VerifyCodeBody {
+    code: String,
+}
+
+async fn activate_user<UIT: UserIdTable>(
+    conn: &mut AsyncPgConnection,
+    user_id: UserId,
+) -> Result<(), RejectReason> {
+    let mut user_id_account =
+        UIT::get(conn, user_id)
+            .await
+            .map_err(|_| RejectReason::NotFound {
+                resource: format!("UserIdAccount {}", user_id),
+            })?;
+    user_id_account
+        .set_account_type(conn, UserAccountType::Active)
+        .await
+        .map_err(RejectReason::database_error)
+}
+
 async fn verify_email_handler<E: UnverifiedEmailTable, U: UserTable, UIT: UserIdTable>(
     auth_user: AuthenticatedUser,
     Query(query): Query<VerifyQuery>,
@@ -55,17 +90,45 @@ async fn verify_email_handler<E: UnverifiedEmailTable, U: UserTable, UIT: UserId
                     serde_json::to_string(&json!({"message": "denied"})).expect("valid json"),
                 ));
             }
-            let mut user_id_account =
-                UIT::get(&mut conn, user.id())
-                    .await
-                    .map_err(|_| RejectReason::NotFound {
-                        resource: format!("UserIdAccount {}", user.id()),
-                    })?;
-            user_id_account
-                .set_account_type(&mut conn, UserAccountType::Active)
-                .await
-                .map_err(RejectReason::database_error)?;
+            activate_user::<UIT>(&mut conn, user.id()).await?;
+
+            Ok((
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/json")],
+                serde_json::to_string(&json!({"message": "verified"})).expect("valid json"),
+            ))
+        }
+        EmailVerification::Denied => Ok((
+            StatusCode::FORBIDDEN,
+            [(header::CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&json!({"message": "denied"})).expect("valid json"),
+        )),
+    }
+}
+
+async fn verify_code_handler<E: UnverifiedEmailTable, U: UserTable, UIT: UserIdTable>(
+    auth_user: AuthenticatedUser,
+    State(app): State<AppState>,
+    Extension(throttle): Extension<Arc<VerificationThrottle>>,
+    Json(body): Json<VerifyCodeBody>,
+) -> Result<impl IntoResponse, RejectReason> {
+    let mut conn = app.db_pool.get().await.map_err(RejectReason::pool_error)?;
 
+    let user = U::get(&mut conn, auth_user.id())
+        .await
+        .ok_or_else(|| RejectReason::not_found(format!("UserTable {}", auth_user.id())))?;
+    let email = EmailAddress::from_str(&user.email())
+        .map_err(|_| RejectReason::bad_request(format!("Invalid user email: {}", user.email())))?;
+    throttle
+        .check_code(&email)
+        .map_err(RejectReason::too_many_requests)?;
+    let checked_verify = E::verify_code(&mut conn, &email, &body.code)
+        .await
+        .map_err(RejectReason::database_error)?;
+
+    match checked_verify {
+        EmailVerification::Accepted(_) => {
+            activate_user::<UIT>(&mut conn, user.id()).await?;
             Ok((
                 StatusCode::OK,
                 [(header::CONTENT_TYPE, "application/json")],
@@ -87,7 +150,10 @@ async fn resend_email_handler<
     U: UserTable,
 >(
     auth_user: AuthenticatedUser,
+    Query(query): Query<ResendQuery>,
     State(app): State<AppState>,
+    Extension(throttle): Extension<Arc<VerificationThrottle>>,
+    headers: HeaderMap,
 ) -> Result<Response, RejectReason> {
     let mut conn = app.db_pool.get().await.map_err(RejectReason::pool_error)?;
     let user = U::get(&mut conn, auth_user.id())
@@ -97,16 +163,49 @@ async fn resend_email_handler<
         Ok(builder) => builder,
         Err(e) => return Ok(AnyhowError::from(e).into_response()),
     };
-    let builder = builder.subject("Verify your email");  // TODO: Move to config, i18n
+    let locale = recipient_locale(&mut conn, user.id(), accept_language(&headers)).await;
+    let builder = builder
+        .locale(&locale)
+        .localized_subject(VERIFY_EMAIL, &locale);
     let email = EmailAddress::from_str(&user.email())
         .map_err(|_| RejectReason::bad_request(format!("Invalid user email: {}", user.email())))?;
+    throttle
+        .check(user.id(), &email)
+        .map_err(RejectReason::too_many_requests)?;
     let email_tx = app.router.announce();
-    if let Err(anyerr) =
-        send_verification_email::<E, B, T, U>(&mut conn, &app.base_url, email, builder, email_tx)
+    let sent = match query.mode {
+        VerificationMode::Link => {
+            send_verification_email::<E, B, T, U>(
+                &mut conn,
+                &app.base_url,
+                email.clone(),
+                builder,
+                email_tx,
+            )
             .await
-    {
+        }
+        VerificationMode::Code => {
+            send_verification_code::<E, B, T, U>(
+                &mut conn,
+                email.clone(),
+                MIN_CODE_DIGITS,
+                builder,
+                email_tx,
+            )
+            .await
+        }
+    };
+    if let Err(anyerr) = sent {
         return Ok(AnyhowError::from(anyerr).into_response());
     }
+    E::invalidate_older(
+        &mut conn,
+        &email,
+        TokenPurpose::Verify,
+        throttle.max_outstanding(),
+    )
+    .await
+    .map_err(RejectReason::database_error)?;
     Ok(Json(&json!({"message": "resent"})).into_response())
 }
 
@@ -117,7 +216,23 @@ pub fn routes<
     U: UserTable + 'static,
     EIT: UserIdTable + 'static,
 >() -> Router<AppState> {
+    routes_with_throttle::<E, B, T, U, EIT>(Arc::new(VerificationThrottle::default()))
+}
+
+/// Like `routes`, but resends and code guesses are limited by `throttle` instead of the default
+/// limits.
+pub fn routes_with_throttle<
+    E: UnverifiedEmailTable + 'static,
+    B: EmailTemplateBuilder<T, U> + Clone + Sync + Send + 'static,
+    T: EmailTemplate + Send + Sync + 'static,
+    U: UserTable + 'static,
+    EIT: UserIdTable + 'static,
+>(
+    throttle: Arc<VerificationThrottle>,
+) -> Router<AppState> {
     Router::new()
         .route("/email/verify", post(verify_email_handler::<E, U, EIT>))
         .route("/email/verify", put(resend_email_handler::<E, B, T, U>))
+        .route("/email/verify/code", post(verify_code_handler::<E, U, EIT>))
+        .layer(Extension(throttle))
 }
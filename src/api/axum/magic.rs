@@ -0,0 +1,157 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use axum_extra::extract::CookieJar as AxumCookieJar;
+use cookie::{Cookie, SameSite};
+use email_address::EmailAddress;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{accept_language, sessions::AUTH_COOKIE, AppState, RejectReason};
+use crate::api::magic::{confirmation_page, sanitize_origin, MagicSession};
+use crate::email::locale::{recipient_locale, SIGN_IN};
+use crate::email::{send_login_email, EmailTemplate, EmailTemplateBuilder, VerificationThrottle};
+use crate::tables::{EmailVerification, TokenPurpose, UnverifiedEmailTable, UserTable};
+
+#[derive(Deserialize)]
+struct MagicLinkRequest {
+    email: String,
+    origin: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    token: String,
+    origin: Option<String>,
+}
+
+async fn request_link_handler<
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U>,
+    T: EmailTemplate + Sync + 'static,
+    U: UserTable,
+>(
+    State(app): State<AppState>,
+    Extension(throttle): Extension<Arc<VerificationThrottle>>,
+    headers: HeaderMap,
+    Json(body): Json<MagicLinkRequest>,
+) -> Result<Response, RejectReason> {
+    let email = EmailAddress::from_str(&body.email)
+        .map_err(|_| RejectReason::bad_request(format!("Invalid email: {}", body.email)))?;
+    // Reply the same way whether or not the address has an account so this can't be used to
+    // discover users: every address is throttled and failures are only logged.
+    throttle
+        .check_address(&email)
+        .map_err(RejectReason::too_many_requests)?;
+    let mut conn = app.db_pool.get().await.map_err(RejectReason::pool_error)?;
+    if let Some(user) = U::from_email(&mut conn, email.as_str()).await {
+        let sent = async {
+            // Owned by the block, so the future doesn't need `U: Sync`.
+            let user = user;
+            let builder = B::new(&mut conn, &user).await?;
+            let locale = recipient_locale(&mut conn, user.id(), accept_language(&headers)).await;
+            let builder = builder.locale(&locale).localized_subject(SIGN_IN, &locale);
+            let origin = sanitize_origin(body.origin.as_deref());
+            send_login_email::<E, B, T, U>(
+                &mut conn,
+                &app.base_url,
+                email.clone(),
+                &origin,
+                builder,
+                app.router.announce(),
+            )
+            .await?;
+            E::invalidate_older(
+                &mut conn,
+                &email,
+                TokenPurpose::Login,
+                throttle.max_outstanding(),
+            )
+            .await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(err) = sent {
+            tracing::error!("Could not send a sign in link to {}: {:#}", email, err);
+        }
+    }
+    Ok(Json(&json!({"message": "sent"})).into_response())
+}
+
+async fn confirm_handler(Query(query): Query<CallbackQuery>) -> impl IntoResponse {
+    let origin = sanitize_origin(query.origin.as_deref());
+    (
+        [
+            (header::CACHE_CONTROL, "no-store"),
+            (header::REFERRER_POLICY, "no-referrer"),
+        ],
+        Html(confirmation_page(&query.token, &origin)),
+    )
+}
+
+async fn callback_handler<E: UnverifiedEmailTable, U: UserTable>(
+    State(app): State<AppState>,
+    jar: AxumCookieJar,
+    Form(query): Form<CallbackQuery>,
+) -> Result<(AxumCookieJar, Response), RejectReason> {
+    let mut conn = app.db_pool.get().await.map_err(RejectReason::pool_error)?;
+    let verified = E::consume_token(&mut conn, &query.token, TokenPurpose::Login)
+        .await
+        .map_err(RejectReason::database_error)?;
+    let email = match verified {
+        EmailVerification::Accepted(email) => email,
+        EmailVerification::Denied => {
+            let denied = (
+                StatusCode::FORBIDDEN,
+                [(header::CONTENT_TYPE, "application/json")],
+                serde_json::to_string(&json!({"message": "denied"})).expect("valid json"),
+            );
+            return Ok((jar, denied.into_response()));
+        }
+    };
+    let user = U::from_email(&mut conn, email.as_str())
+        .await
+        .ok_or_else(|| RejectReason::not_found(format!("UserTable {}", email)))?;
+    let token = MagicSession::new(user.id(), &email)
+        .sign()
+        .map_err(RejectReason::anyhow)?;
+    let cookie = Cookie::build((
+        AUTH_COOKIE,
+        serde_json::to_string(&token).expect("serialize token"),
+    ))
+    .path("/")
+    .http_only(true)
+    .same_site(SameSite::Lax)
+    .secure(true)
+    .build();
+
+    let origin = sanitize_origin(query.origin.as_deref());
+    Ok((jar.add(cookie), Redirect::to(&origin).into_response()))
+}
+
+/// Passwordless sign in. `POST /auth/magic` emails a single-use link to the user with that
+/// address. The link opens a page which posts the token back to sign in, setting the same auth
+/// cookie as the OIDC `/auth` route.
+pub fn routes<
+    E: UnverifiedEmailTable + 'static,
+    B: EmailTemplateBuilder<T, U> + Clone + Sync + Send + 'static,
+    T: EmailTemplate + Send + Sync + 'static,
+    U: UserTable + 'static,
+>(
+    throttle: Arc<VerificationThrottle>,
+) -> Router<AppState> {
+    Router::new()
+        .route("/auth/magic", post(request_link_handler::<E, B, T, U>))
+        .route(
+            "/auth/magic/callback",
+            get(confirm_handler).post(callback_handler::<E, U>),
+        )
+        .layer(Extension(throttle))
+}
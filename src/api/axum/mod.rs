@@ -1,10 +1,14 @@
 pub mod email;
+pub mod magic;
+pub mod preview;
 pub mod sessions;
+pub mod webhooks;
 
 use std::sync::Arc;
 
 use axum::{
-    http::{header, StatusCode},
+    extract::FromRef,
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
 use serde_json::json;
@@ -20,6 +24,18 @@ pub struct AppState {
     pub base_url: String,
 }
 
+impl FromRef<AppState> for Arc<DbPool> {
+    fn from_ref(app: &AppState) -> Self {
+        app.db_pool.clone()
+    }
+}
+
+fn accept_language(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+}
+
 impl IntoResponse for AnyhowError {
     fn into_response(self) -> Response {
         tracing::warn!("AnyhowError: {:?}", self.error);
@@ -63,6 +79,18 @@ impl IntoResponse for RejectReason {
                 serde_json::to_string(&json!({"error": resource})).expect("valid json"),
             )
                 .into_response(),
+            RejectReason::TooManyRequests { retry_after } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [
+                    (header::CONTENT_TYPE, "application/json".to_string()),
+                    (
+                        header::RETRY_AFTER,
+                        RejectReason::retry_after_secs(retry_after).to_string(),
+                    ),
+                ],
+                serde_json::to_string(&json!({"error": "too many requests"})).expect("valid json"),
+            )
+                .into_response(),
             RejectReason::Anyhow { error } => error.into_response(),
             _ => {
                 tracing::error!("RejectReason: {:?}", self);
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Path},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_json::Value;
+
+use super::{AppState, RejectReason};
+use crate::email::preview::{EmailPreview, Preview};
+
+const BASE_PATH: &str = "/email/preview";
+
+fn into_response(preview: Preview) -> Response {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, preview.content_type)],
+        preview.body,
+    )
+        .into_response()
+}
+
+async fn index_handler(Extension(preview): Extension<Arc<EmailPreview>>) -> Response {
+    into_response(preview.index(BASE_PATH))
+}
+
+fn render(
+    preview: &EmailPreview,
+    template: &str,
+    data: Option<Value>,
+) -> Result<Response, RejectReason> {
+    match preview.render(template, data) {
+        Ok(Some(rendered)) => Ok(into_response(rendered)),
+        Ok(None) => Err(RejectReason::not_found(format!("Template {}", template))),
+        // Show template errors to the designer rather than hiding them in the logs.
+        Err(err) => Ok((StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", err)).into_response()),
+    }
+}
+
+async fn template_handler(
+    Extension(preview): Extension<Arc<EmailPreview>>,
+    Path(template): Path<String>,
+) -> Result<Response, RejectReason> {
+    render(&preview, &template, None)
+}
+
+async fn template_data_handler(
+    Extension(preview): Extension<Arc<EmailPreview>>,
+    Path(template): Path<String>,
+    Json(data): Json<Value>,
+) -> Result<Response, RejectReason> {
+    render(&preview, &template, Some(data))
+}
+
+async fn sent_handler(
+    Extension(preview): Extension<Arc<EmailPreview>>,
+    Path(index): Path<usize>,
+) -> Result<Response, RejectReason> {
+    preview
+        .sent(index)
+        .map(into_response)
+        .ok_or_else(|| RejectReason::not_found(format!("Sent email {}", index)))
+}
+
+/// Development previews of the email templates and of recently sent emails, starting at
+/// `GET /email/preview`. `GET /email/preview/templates/:name` renders a template with its
+/// fixture and `POST` renders it with the JSON body. Only mount these in development.
+pub fn routes(preview: Arc<EmailPreview>) -> Router<AppState> {
+    Router::new()
+        .route(BASE_PATH, get(index_handler))
+        .route(
+            "/email/preview/templates/:template",
+            get(template_handler).post(template_data_handler),
+        )
+        .route("/email/preview/sent/:index", get(sent_handler))
+        .layer(Extension(preview))
+}
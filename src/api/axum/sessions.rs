@@ -26,7 +26,7 @@ use urlencoding::decode;
 use crate::oidc::OidcToken;
 
 use super::{AppState, RejectReason};
-use crate::api::{AuthRejectReason, AuthenticatedUser, ValidatesIdentity};
+use crate::api::{magic, AuthRejectReason, AuthenticatedUser, ValidatesIdentity};
 
 pub const AUTH_COOKIE: &str = "access_token";
 
@@ -102,6 +102,17 @@ where
             Some(token)
         } else {
             let auth_cookie = cookies.get(AUTH_COOKIE);
+            // Sessions from magic links are signed by us and don't involve the identity provider.
+            if let Some(magic_user) =
+                auth_cookie.and_then(|cookie| magic::authenticate_cookie(cookie.value()))
+            {
+                return magic_user
+                    .map_err(|err| {
+                        tracing::debug!("Invalid magic session: {}", err);
+                        err
+                    })
+                    .ok();
+            }
             if let Some(auth_cookie) = auth_cookie {
                 Some(
                     parse_auth_cookie(auth_cookie.value())
@@ -330,24 +341,28 @@ async fn logout(
     session.delete().await.ok();
     let token = jar.get(AUTH_COOKIE);
     if let Some(token) = token {
+        if magic::MagicToken::parse(token.value()).is_some() {
+            return Ok(clear_auth_cookie(Redirect::to("/").into_response()));
+        }
         let oidc_token =
             parse_auth_cookie(token.value()).map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
         let logout_url = app.idp.logout_oidc("/", &oidc_token);
         let uri = logout_url.as_str();
-        let mut response = Redirect::to(uri).into_response();
-        {
-            let headers = response.headers_mut();
-            headers.insert(CACHE_CONTROL, "no-store, must-revalidate".parse().unwrap());
-            headers.insert(EXPIRES, "0".parse().unwrap());
-            let cookie = format!("{}=; Max-Age=0; Path=/; HttpOnly; Secure", AUTH_COOKIE);
-            headers.insert(SET_COOKIE, cookie.parse().unwrap());
-        }
-        Ok(response)
+        Ok(clear_auth_cookie(Redirect::to(uri).into_response()))
     } else {
         Err(StatusCode::UNAUTHORIZED)
     }
 }
 
+fn clear_auth_cookie(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(CACHE_CONTROL, "no-store, must-revalidate".parse().unwrap());
+    headers.insert(EXPIRES, "0".parse().unwrap());
+    let cookie = format!("{}=; Max-Age=0; Path=/; HttpOnly; Secure", AUTH_COOKIE);
+    headers.insert(SET_COOKIE, cookie.parse().unwrap());
+    response
+}
+
 pub fn routes(store: MemoryStore) -> Router<AppState> {
     let layer = SessionManagerLayer::new(store)
         .with_secure(false)
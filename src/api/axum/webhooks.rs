@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Extension, FromRef, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::RejectReason;
+use crate::email::suppression::{record_webhook, WebhookProvider, WebhookSecret};
+use crate::tables::DbPool;
+
+#[derive(Deserialize)]
+struct WebhookQuery {
+    token: String,
+}
+
+async fn webhook_handler(
+    State(db_pool): State<Arc<DbPool>>,
+    Extension(secret): Extension<Arc<WebhookSecret>>,
+    Path(provider): Path<String>,
+    Query(query): Query<WebhookQuery>,
+    body: Bytes,
+) -> Result<Response, RejectReason> {
+    if !secret.verify(&query.token) {
+        return Ok((StatusCode::FORBIDDEN, Json(json!({"message": "denied"}))).into_response());
+    }
+    let provider: WebhookProvider = provider
+        .parse()
+        .map_err(|_| RejectReason::not_found(format!("Webhook {}", provider)))?;
+    let body: Value = serde_json::from_slice(&body)
+        .map_err(|err| RejectReason::bad_request(format!("Invalid webhook body: {}", err)))?;
+    let mut conn = db_pool.get().await.map_err(RejectReason::pool_error)?;
+    let suppressed = record_webhook(&mut conn, provider, &body)
+        .await
+        .map_err(RejectReason::anyhow)?;
+    Ok(Json(json!({"suppressed": suppressed})).into_response())
+}
+
+/// Bounce and complaint notifications. Point the provider at
+/// `POST /email/webhooks/{ses,sendgrid,postmark}?token=<secret>`. Bodies are read as JSON
+/// whatever their content type, since SNS posts it as `text/plain`.
+pub fn routes<S>(secret: Arc<WebhookSecret>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    Arc<DbPool>: FromRef<S>,
+{
+    Router::new()
+        .route("/email/webhooks/:provider", post(webhook_handler))
+        .layer(Extension(secret))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Request};
+    use function_name::named;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::tables::establish_connection_pool;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[tokio::test]
+    #[named]
+    async fn test_sns_webhook_route() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let pool = establish_connection_pool(&harness.db_conf.db_url(&harness.db_name), false)
+            .await
+            .expect("pool");
+        let secret = "s".repeat(32);
+        let app = routes(Arc::new(WebhookSecret::new(&secret).unwrap())).with_state(Arc::new(pool));
+
+        let bounce = json!({
+            "notificationType": "Bounce",
+            "bounce": {
+                "bounceType": "Permanent",
+                "bouncedRecipients": [{"emailAddress": "gone@example.com"}],
+            },
+        });
+        let sns = json!({"Type": "Notification", "Message": bounce.to_string()});
+        let request = Request::post(format!("/email/webhooks/ses?token={}", secret))
+            .header(header::CONTENT_TYPE, "text/plain; charset=UTF-8")
+            .body(Body::from(sns.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({"suppressed": 1})
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, bail, Result as AnyResult};
+use email_address::EmailAddress;
+use handlebars::html_escape;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use super::AuthenticatedUser;
+use crate::tables::UserId;
+
+#[cfg(feature = "axum")]
+pub use super::axum::magic::*;
+#[cfg(feature = "warp")]
+pub use super::warp::magic::*;
+
+/// How long a session started from a magic link lasts.
+pub const MAGIC_SESSION_SECS: i64 = 24 * 60 * 60;
+const MIN_KEY_BYTES: usize = 32;
+
+static SESSION_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Set the key used to sign magic link sessions. This must be called once at startup with the
+/// same key on every replica; magic link routes refuse to sign in until it has been set.
+pub fn init_session_key(key: &[u8]) -> AnyResult<()> {
+    if key.len() < MIN_KEY_BYTES {
+        bail!("Session key must be at least {} bytes", MIN_KEY_BYTES);
+    }
+    SESSION_KEY
+        .set(key.to_vec())
+        .map_err(|_| anyhow!("Session key is already set"))
+}
+
+fn mac() -> AnyResult<Hmac<Sha256>> {
+    let key = SESSION_KEY
+        .get()
+        .ok_or_else(|| anyhow!("Session key is not set"))?;
+    Ok(Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size"))
+}
+
+/// A session signed by this service rather than issued by an identity provider.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MagicSession {
+    pub user_id: Uuid,
+    pub email: String,
+    /// Unix timestamp after which the session is rejected.
+    pub expires: i64,
+}
+
+impl MagicSession {
+    pub fn new(user_id: UserId, email: &EmailAddress) -> Self {
+        Self {
+            user_id: user_id.0,
+            email: email.to_string(),
+            expires: chrono::Utc::now().timestamp() + MAGIC_SESSION_SECS,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        chrono::Utc::now().timestamp() <= self.expires
+    }
+
+    pub fn sign(&self) -> AnyResult<MagicToken> {
+        let payload = base64::encode_config(serde_json::to_vec(self)?, base64::URL_SAFE_NO_PAD);
+        let mut mac = mac()?;
+        mac.update(payload.as_bytes());
+        let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        Ok(MagicToken {
+            magic: format!("{}.{}", payload, signature),
+        })
+    }
+}
+
+/// The signed form of a `MagicSession`, stored in the auth cookie in place of an `OidcToken`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MagicToken {
+    magic: String,
+}
+
+impl MagicToken {
+    /// Parse an auth cookie, returning `None` if it holds some other kind of token.
+    pub fn parse(cookie: &str) -> Option<Self> {
+        serde_json::from_str(cookie).ok()
+    }
+
+    pub fn verify(&self) -> AnyResult<MagicSession> {
+        let (payload, signature) = self
+            .magic
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Malformed magic session"))?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)?;
+        let mut mac = mac()?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| anyhow!("Invalid magic session signature"))?;
+        let session: MagicSession =
+            serde_json::from_slice(&base64::decode_config(payload, base64::URL_SAFE_NO_PAD)?)?;
+        if !session.is_valid() {
+            bail!("Magic session expired");
+        }
+        Ok(session)
+    }
+}
+
+impl From<MagicSession> for AuthenticatedUser {
+    fn from(session: MagicSession) -> Self {
+        Self {
+            id: session.user_id,
+            username: session.email.clone(),
+            email: session.email,
+            email_verified: true,
+            given_name: None,
+            family_name: None,
+        }
+    }
+}
+
+/// Authenticate an auth cookie holding a magic session. Returns `None` for any other cookie so
+/// the caller can fall back to the identity provider.
+pub fn authenticate_cookie(cookie: &str) -> Option<AnyResult<AuthenticatedUser>> {
+    let token = MagicToken::parse(cookie)?;
+    Some(token.verify().map(AuthenticatedUser::from))
+}
+
+/// Only redirect back to paths on this site after signing in, and only to paths which are
+/// valid in a `Location` header.
+pub fn sanitize_origin(origin: Option<&str>) -> String {
+    match origin {
+        Some(origin)
+            if origin.starts_with('/')
+                && !origin.starts_with("//")
+                && !origin.starts_with("/\\")
+                && !origin.chars().any(|c| c.is_control() || c.is_whitespace()) =>
+        {
+            origin.to_string()
+        }
+        _ => String::from("/"),
+    }
+}
+
+/// The page the emailed link opens, which signs in by posting the token back. Consuming the
+/// token only on submit keeps link scanners which prefetch the link from using it up.
+pub fn confirmation_page(token: &str, origin: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Sign in</title></head><body>\
+         <form method=\"post\"><input type=\"hidden\" name=\"token\" value=\"{}\">\
+         <input type=\"hidden\" name=\"origin\" value=\"{}\">\
+         <button type=\"submit\">Sign in</button></form></body></html>",
+        html_escape(token),
+        html_escape(origin)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_magic_session_signature() {
+        init_session_key(&[7u8; 32]).ok();
+        let email = EmailAddress::from_str("test@example.com").expect("valid email");
+        let session = MagicSession::new(UserId(Uuid::new_v4()), &email);
+        let token = session.sign().expect("signed");
+        let cookie = serde_json::to_string(&token).expect("serialized");
+
+        let user = authenticate_cookie(&cookie)
+            .expect("magic cookie")
+            .expect("valid session");
+        assert_eq!(user.id().0, session.user_id);
+        assert_eq!(user.email(), "test@example.com");
+
+        let mut forged = session.clone();
+        forged.user_id = Uuid::new_v4();
+        let (_, signature) = token.magic.split_once('.').expect("signed");
+        let payload = base64::encode_config(
+            serde_json::to_vec(&forged).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let forged = MagicToken {
+            magic: format!("{}.{}", payload, signature),
+        };
+        assert!(forged.verify().is_err());
+
+        let mut expired = session;
+        expired.expires = chrono::Utc::now().timestamp() - 1;
+        assert!(expired.sign().expect("signed").verify().is_err());
+
+        assert!(authenticate_cookie("{\"id_token\": \"\"}").is_none());
+        assert_eq!(sanitize_origin(Some("//evil.example")), "/");
+        assert_eq!(sanitize_origin(Some("/app")), "/app");
+        assert_eq!(sanitize_origin(Some("/\n")), "/");
+        assert_eq!(sanitize_origin(Some("/a b")), "/");
+
+        let page = confirmation_page("t\"><script>", "/app");
+        assert!(page.contains("<form method=\"post\">"));
+        assert!(page.contains("value=\"t&quot;&gt;&lt;script&gt;\""));
+    }
+}
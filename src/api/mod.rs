@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use anyhow::{anyhow, Context, Result as AnyResult};
 use email_address::EmailAddress;
 use openidconnect::core::CoreIdTokenClaims;
@@ -12,6 +14,8 @@ mod axum;
 #[cfg(feature = "warp")]
 mod warp;
 
+pub mod magic;
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum AuthRejectReason {
@@ -150,6 +154,24 @@ pub mod email {
     pub use super::axum::email::*;
 }
 
+#[cfg(any(feature = "warp", feature = "axum"))]
+pub mod preview {
+    #[cfg(feature = "warp")]
+    pub use super::warp::preview::*;
+
+    #[cfg(feature = "axum")]
+    pub use super::axum::preview::*;
+}
+
+#[cfg(any(feature = "warp", feature = "axum"))]
+pub mod webhooks {
+    #[cfg(feature = "warp")]
+    pub use super::warp::webhooks::*;
+
+    #[cfg(feature = "axum")]
+    pub use super::axum::webhooks::*;
+}
+
 #[derive(Debug)]
 pub struct AnyhowError {
     pub error: anyhow::Error,
@@ -178,6 +200,7 @@ pub enum RejectReason {
     MissingEnvKey { key: String },
     NotFound { resource: String },
     Session,
+    TooManyRequests { retry_after: Duration },
 }
 
 impl RejectReason {
@@ -230,10 +253,21 @@ impl RejectReason {
     pub fn session() -> Self {
         RejectReason::Session
     }
+
+    pub fn too_many_requests(retry_after: Duration) -> Self {
+        RejectReason::TooManyRequests { retry_after }
+    }
+
+    /// The whole number of seconds for a `Retry-After` header, rounded up.
+    pub fn retry_after_secs(retry_after: Duration) -> u64 {
+        retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+    }
 }
 
 #[cfg(feature = "axum")]
 pub use axum::AppState;
 
 #[cfg(feature = "warp")]
-pub use warp::{handle_rejection, init_session_store, with_broadcast, with_db, with_string};
+pub use warp::{
+    handle_rejection, init_session_store, with_broadcast, with_db, with_string, with_throttle,
+};
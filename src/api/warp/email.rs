@@ -1,6 +1,7 @@
 use std::str::FromStr;
 use std::sync::Arc;
 
+use diesel_async::AsyncPgConnection;
 use email_address::EmailAddress;
 use serde::Deserialize;
 use serde_json::json;
@@ -8,22 +9,54 @@ use tokio::sync::broadcast;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 use warp_sessions::{MemoryStore, SessionWithStore};
 
-use super::with_db;
+use super::{with_accept_language, with_db, with_throttle};
 use crate::api::{authenticate, with_broadcast, with_string, AnyhowError, RejectReason};
 use crate::api::{sessions::store_auth_cookie, AuthenticatedUser};
-use crate::email::{EmailTemplate, EmailTemplateBuilder, ScheduledEmail};
+use crate::email::locale::{recipient_locale, VERIFY_EMAIL};
+use crate::email::{
+    EmailTemplate, EmailTemplateBuilder, ScheduledEmail, VerificationMode, VerificationThrottle,
+};
 use crate::oidc::IdentityProvider;
 use crate::tables::{
-    DbPool, EmailVerification, UnverifiedEmailTable, UserAccountType, UserIdTable, UserTable,
+    DbPool, EmailVerification, TokenPurpose, UnverifiedEmailTable, UserAccountType, UserId,
+    UserIdTable, UserTable, MIN_CODE_DIGITS,
 };
 
-use crate::email::send_verification_email;
+use crate::email::{send_verification_code, send_verification_email};
 
 #[derive(Deserialize)]
 struct VerifyQuery {
     id: String,
 }
 
+#[derive(Deserialize)]
+struct ResendQuery {
+    #[serde(default)]
+    mode: VerificationMode,
+}
+
+#[derive(Deserialize)]
+struct VerifyCodeBody {
+    code: String,
+}
+
+async fn activate_user<UIT: UserIdTable>(
+    conn: &mut AsyncPgConnection,
+    user_id: UserId,
+) -> Result<(), Rejection> {
+    let mut user_id_account =
+        UIT::get(conn, user_id)
+            .await
+            .map_err(|_| RejectReason::NotFound {
+                resource: format!("UserIdAccount {}", user_id),
+            })?;
+    user_id_account
+        .set_account_type(conn, UserAccountType::Active)
+        .await
+        .map_err(RejectReason::database_error)?;
+    Ok(())
+}
+
 async fn verify_email_handler<E: UnverifiedEmailTable, U: UserTable, UIT: UserIdTable>(
     query: VerifyQuery,
     auth: AuthenticatedUser,
@@ -60,16 +93,7 @@ async fn verify_email_handler<E: UnverifiedEmailTable, U: UserTable, UIT: UserId
                     session,
                 ));
             }
-            let mut user_id_account =
-                UIT::get(&mut conn, user.id())
-                    .await
-                    .map_err(|_| RejectReason::NotFound {
-                        resource: format!("UserIdAccount {}", user.id()),
-                    })?;
-            user_id_account
-                .set_account_type(&mut conn, UserAccountType::Active)
-                .await
-                .map_err(RejectReason::database_error)?;
+            activate_user::<UIT>(&mut conn, user.id()).await?;
 
             Ok((
                 warp::reply::with_status(
@@ -89,28 +113,110 @@ async fn verify_email_handler<E: UnverifiedEmailTable, U: UserTable, UIT: UserId
     }
 }
 
+async fn verify_code_handler<E: UnverifiedEmailTable, U: UserTable, UIT: UserIdTable>(
+    body: VerifyCodeBody,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+    throttle: Arc<VerificationThrottle>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = db_pool.get().await.map_err(RejectReason::pool_error)?;
+
+    let user = U::get(&mut conn, auth.id())
+        .await
+        .ok_or_else(|| RejectReason::not_found(format!("UserTable {}", auth.id())))?;
+    let email = EmailAddress::from_str(&user.email())
+        .map_err(|_| RejectReason::bad_request(format!("Invalid user email: {}", user.email())))?;
+    throttle
+        .check_code(&email)
+        .map_err(RejectReason::too_many_requests)?;
+    let checked_verify = E::verify_code(&mut conn, &email, &body.code)
+        .await
+        .map_err(RejectReason::database_error)?;
+
+    match checked_verify {
+        EmailVerification::Accepted(_) => {
+            activate_user::<UIT>(&mut conn, user.id()).await?;
+            Ok((
+                warp::reply::with_status(
+                    warp::reply::json(&json!({"message": "verified"})),
+                    StatusCode::OK,
+                ),
+                session,
+            ))
+        }
+        EmailVerification::Denied => Ok((
+            warp::reply::with_status(
+                warp::reply::json(&json!({"message": "denied"})),
+                StatusCode::FORBIDDEN,
+            ),
+            session,
+        )),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn resend_email_handler<
     E: UnverifiedEmailTable,
     B: EmailTemplateBuilder<T, U>,
     T: EmailTemplate,
     U: UserTable,
 >(
+    query: ResendQuery,
     auth: AuthenticatedUser,
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
     email_tx: broadcast::Sender<ScheduledEmail<T>>,
     base_url: String,
+    throttle: Arc<VerificationThrottle>,
+    accept_language: Option<String>,
 ) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
     let mut conn = db_pool.get().await.map_err(RejectReason::pool_error)?;
     let user = U::get(&mut conn, auth.id())
         .await
         .ok_or_else(|| RejectReason::not_found(format!("UserTable {}", auth.id())))?;
-    let builder = B::new(&mut conn, &user).await.map_err(AnyhowError::from)?;
+    let locale = recipient_locale(&mut conn, user.id(), accept_language.as_deref()).await;
+    let builder = B::new(&mut conn, &user)
+        .await
+        .map_err(AnyhowError::from)?
+        .locale(&locale)
+        .localized_subject(VERIFY_EMAIL, &locale);
     let email = EmailAddress::from_str(&user.email())
         .map_err(|_| RejectReason::bad_request(format!("Invalid user email: {}", user.email())))?;
-    send_verification_email::<E, B, T, U>(&mut conn, &base_url, email, builder, email_tx)
-        .await
-        .map_err(AnyhowError::from)?;
+    throttle
+        .check(user.id(), &email)
+        .map_err(RejectReason::too_many_requests)?;
+    match query.mode {
+        VerificationMode::Link => {
+            send_verification_email::<E, B, T, U>(
+                &mut conn,
+                &base_url,
+                email.clone(),
+                builder,
+                email_tx,
+            )
+            .await
+        }
+        VerificationMode::Code => {
+            send_verification_code::<E, B, T, U>(
+                &mut conn,
+                email.clone(),
+                MIN_CODE_DIGITS,
+                builder,
+                email_tx,
+            )
+            .await
+        }
+    }
+    .map_err(AnyhowError::from)?;
+    E::invalidate_older(
+        &mut conn,
+        &email,
+        TokenPurpose::Verify,
+        throttle.max_outstanding(),
+    )
+    .await
+    .map_err(RejectReason::database_error)?;
     Ok((warp::reply::json(&json!({"message": "resent"})), session))
 }
 
@@ -126,6 +232,32 @@ pub fn routes<
     pool: Arc<DbPool>,
     base_url: String,
     email_tx: broadcast::Sender<ScheduledEmail<T>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    routes_with_throttle::<E, B, T, U, EIT>(
+        idp,
+        session,
+        pool,
+        base_url,
+        email_tx,
+        Arc::new(VerificationThrottle::default()),
+    )
+}
+
+/// Like `routes`, but resends and code guesses are limited by `throttle` instead of the default
+/// limits.
+pub fn routes_with_throttle<
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U> + Clone + Sync + Send + 'static,
+    T: EmailTemplate + Send + Sync,
+    U: UserTable,
+    EIT: UserIdTable,
+>(
+    idp: Option<Arc<IdentityProvider>>,
+    session: MemoryStore,
+    pool: Arc<DbPool>,
+    base_url: String,
+    email_tx: broadcast::Sender<ScheduledEmail<T>>,
+    throttle: Arc<VerificationThrottle>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let verify_email = warp::path!("email" / "verify")
         .and(warp::post())
@@ -136,15 +268,28 @@ pub fn routes<
         .untuple_one()
         .and_then(store_auth_cookie);
 
+    let verify_code = warp::path!("email" / "verify" / "code")
+        .and(warp::post())
+        .and(warp::body::json::<VerifyCodeBody>())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and(with_throttle(throttle.clone()))
+        .and_then(verify_code_handler::<E, U, EIT>)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
     let resend_email = warp::path!("email" / "verify")
         .and(warp::put())
+        .and(warp::query::<ResendQuery>())
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
         .and(with_broadcast(email_tx.clone()))
         .and(with_string(base_url.clone()))
+        .and(with_throttle(throttle))
+        .and(with_accept_language())
         .and_then(resend_email_handler::<E, B, T, U>)
         .untuple_one()
         .and_then(store_auth_cookie);
 
-    return verify_email.or(resend_email);
+    return verify_email.or(verify_code).or(resend_email);
 }
@@ -0,0 +1,177 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use cookie::{Cookie, SameSite};
+use email_address::EmailAddress;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{sessions::AUTH_COOKIE, with_accept_language, with_db, with_throttle};
+use crate::api::magic::{confirmation_page, sanitize_origin, MagicSession};
+use crate::api::{with_broadcast, with_string, AnyhowError, RejectReason};
+use crate::email::locale::{recipient_locale, SIGN_IN};
+use crate::email::{
+    send_login_email, EmailTemplate, EmailTemplateBuilder, ScheduledEmail, VerificationThrottle,
+};
+use crate::tables::{DbPool, EmailVerification, TokenPurpose, UnverifiedEmailTable, UserTable};
+
+#[derive(Deserialize)]
+struct MagicLinkRequest {
+    email: String,
+    origin: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    token: String,
+    origin: Option<String>,
+}
+
+async fn request_link_handler<
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U>,
+    T: EmailTemplate,
+    U: UserTable,
+>(
+    body: MagicLinkRequest,
+    db_pool: Arc<DbPool>,
+    email_tx: broadcast::Sender<ScheduledEmail<T>>,
+    base_url: String,
+    throttle: Arc<VerificationThrottle>,
+    accept_language: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    let email = EmailAddress::from_str(&body.email)
+        .map_err(|_| RejectReason::bad_request(format!("Invalid email: {}", body.email)))?;
+    // Reply the same way whether or not the address has an account so this can't be used to
+    // discover users: every address is throttled and failures are only logged.
+    throttle
+        .check_address(&email)
+        .map_err(RejectReason::too_many_requests)?;
+    let mut conn = db_pool.get().await.map_err(RejectReason::pool_error)?;
+    if let Some(user) = U::from_email(&mut conn, email.as_str()).await {
+        let sent = async {
+            // Owned by the block, so the future doesn't need `U: Sync`.
+            let user = user;
+            let locale = recipient_locale(&mut conn, user.id(), accept_language.as_deref()).await;
+            let builder = B::new(&mut conn, &user)
+                .await?
+                .locale(&locale)
+                .localized_subject(SIGN_IN, &locale);
+            let origin = sanitize_origin(body.origin.as_deref());
+            send_login_email::<E, B, T, U>(
+                &mut conn,
+                &base_url,
+                email.clone(),
+                &origin,
+                builder,
+                email_tx,
+            )
+            .await?;
+            E::invalidate_older(
+                &mut conn,
+                &email,
+                TokenPurpose::Login,
+                throttle.max_outstanding(),
+            )
+            .await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(err) = sent {
+            tracing::error!("Could not send a sign in link to {}: {:#}", email, err);
+        }
+    }
+    Ok(warp::reply::json(&json!({"message": "sent"})))
+}
+
+fn confirm(query: CallbackQuery) -> impl Reply {
+    let origin = sanitize_origin(query.origin.as_deref());
+    let page = warp::reply::html(confirmation_page(&query.token, &origin));
+    let page = warp::reply::with_header(page, "Cache-Control", "no-store");
+    warp::reply::with_header(page, "Referrer-Policy", "no-referrer")
+}
+
+async fn callback_handler<E: UnverifiedEmailTable, U: UserTable>(
+    query: CallbackQuery,
+    db_pool: Arc<DbPool>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let mut conn = db_pool.get().await.map_err(RejectReason::pool_error)?;
+    let verified = E::consume_token(&mut conn, &query.token, TokenPurpose::Login)
+        .await
+        .map_err(RejectReason::database_error)?;
+    let email = match verified {
+        EmailVerification::Accepted(email) => email,
+        EmailVerification::Denied => {
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&json!({"message": "denied"})),
+                StatusCode::FORBIDDEN,
+            )));
+        }
+    };
+    let user = U::from_email(&mut conn, email.as_str())
+        .await
+        .ok_or_else(|| RejectReason::not_found(format!("UserTable {}", email)))?;
+    let token = MagicSession::new(user.id(), &email)
+        .sign()
+        .map_err(AnyhowError::from)?;
+    let cookie = Cookie::build((
+        AUTH_COOKIE,
+        serde_json::to_string(&token).expect("serialize token"),
+    ))
+    .path("/")
+    .http_only(true)
+    .same_site(SameSite::Lax)
+    .secure(true)
+    .build();
+
+    let origin: warp::http::Uri = sanitize_origin(query.origin.as_deref())
+        .parse()
+        .map_err(|_| RejectReason::bad_request("Invalid origin"))?;
+    let reply = warp::reply::with_header(
+        warp::redirect::see_other(origin),
+        "Set-Cookie",
+        cookie.to_string(),
+    );
+    Ok(Box::new(reply))
+}
+
+/// Passwordless sign in. `POST /auth/magic` emails a single-use link to the user with that
+/// address. The link opens a page which posts the token back to sign in, setting the same auth
+/// cookie as the OIDC `/auth` route.
+pub fn routes<
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U> + Clone + Sync + Send + 'static,
+    T: EmailTemplate + Send + Sync,
+    U: UserTable,
+>(
+    pool: Arc<DbPool>,
+    base_url: String,
+    email_tx: broadcast::Sender<ScheduledEmail<T>>,
+    throttle: Arc<VerificationThrottle>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let request_link = warp::path!("auth" / "magic")
+        .and(warp::post())
+        .and(warp::body::json::<MagicLinkRequest>())
+        .and(with_db(pool.clone()))
+        .and(with_broadcast(email_tx))
+        .and(with_string(base_url))
+        .and(with_throttle(throttle))
+        .and(with_accept_language())
+        .and_then(request_link_handler::<E, B, T, U>);
+
+    let confirm = warp::path!("auth" / "magic" / "callback")
+        .and(warp::get())
+        .and(warp::query::<CallbackQuery>())
+        .map(confirm);
+
+    let callback = warp::path!("auth" / "magic" / "callback")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::form::<CallbackQuery>())
+        .and(with_db(pool))
+        .and_then(callback_handler::<E, U>);
+
+    request_link.or(confirm).or(callback)
+}
@@ -1,5 +1,8 @@
 pub mod email;
+pub mod magic;
+pub mod preview;
 pub mod sessions;
+pub mod webhooks;
 
 use std::convert::Infallible;
 use std::string::ToString;
@@ -12,6 +15,7 @@ use warp::{http::StatusCode, Filter, Rejection, Reply};
 use warp_sessions::MemoryStore;
 
 use super::{AnyhowError, AuthRejectReason, RejectReason};
+use crate::email::VerificationThrottle;
 use crate::tables::DbPool;
 
 pub fn init_session_store() -> MemoryStore {
@@ -39,6 +43,17 @@ pub fn with_string<S: Send + Sync + Clone + ToString>(
     warp::any().map(move || string.to_string())
 }
 
+pub fn with_throttle(
+    throttle: Arc<VerificationThrottle>,
+) -> impl Filter<Extract = (Arc<VerificationThrottle>,), Error = Infallible> + Clone {
+    warp::any().map(move || throttle.clone())
+}
+
+pub fn with_accept_language() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone
+{
+    warp::header::optional::<String>("accept-language")
+}
+
 pub fn with_broadcast<M: Send + Sync + Clone + 'static>(
     sender: broadcast::Sender<M>,
 ) -> impl Filter<Extract = (broadcast::Sender<M>,), Error = Infallible> + Clone {
@@ -169,6 +184,17 @@ pub async fn handle_rejection(
                     warp::reply::with_status(json, warp::http::StatusCode::INTERNAL_SERVER_ERROR);
                 return Ok(Box::new(response));
             }
+            RejectReason::TooManyRequests { retry_after } => {
+                let json = warp::reply::json(&json!({"error": "too many requests"}));
+                let response =
+                    warp::reply::with_status(json, warp::http::StatusCode::TOO_MANY_REQUESTS);
+                let response = warp::reply::with_header(
+                    response,
+                    "Retry-After",
+                    RejectReason::retry_after_secs(*retry_after).to_string(),
+                );
+                return Ok(Box::new(response));
+            }
         }
     }
 
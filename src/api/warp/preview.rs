@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+use warp::{
+    http::{header, StatusCode},
+    Filter, Rejection, Reply,
+};
+
+use crate::api::RejectReason;
+use crate::email::preview::{EmailPreview, Preview};
+
+const BASE_PATH: &str = "/email/preview";
+
+fn into_reply(preview: Preview) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_header(
+        preview.body,
+        header::CONTENT_TYPE,
+        preview.content_type,
+    ))
+}
+
+fn render(
+    preview: &EmailPreview,
+    template: &str,
+    data: Option<Value>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    match preview.render(template, data) {
+        Ok(Some(rendered)) => Ok(into_reply(rendered)),
+        Ok(None) => Err(RejectReason::not_found(format!("Template {}", template)).into_rejection()),
+        // Show template errors to the designer rather than hiding them in the logs.
+        Err(err) => Ok(Box::new(warp::reply::with_status(
+            format!("{:#}", err),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ))),
+    }
+}
+
+async fn template_handler(
+    template: String,
+    preview: Arc<EmailPreview>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    render(&preview, &template, None)
+}
+
+async fn template_data_handler(
+    template: String,
+    data: Value,
+    preview: Arc<EmailPreview>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    render(&preview, &template, Some(data))
+}
+
+async fn sent_handler(
+    index: usize,
+    preview: Arc<EmailPreview>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    preview
+        .sent(index)
+        .map(into_reply)
+        .ok_or_else(|| RejectReason::not_found(format!("Sent email {}", index)).into_rejection())
+}
+
+/// Development previews of the email templates and of recently sent emails, starting at
+/// `GET /email/preview`. `GET /email/preview/templates/:name` renders a template with its
+/// fixture and `POST` renders it with the JSON body. Only mount these in development.
+pub fn routes(
+    preview: Arc<EmailPreview>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let with_preview = warp::any().map(move || preview.clone());
+
+    let index = warp::path!("email" / "preview")
+        .and(warp::get())
+        .and(with_preview.clone())
+        .map(|preview: Arc<EmailPreview>| into_reply(preview.index(BASE_PATH)));
+
+    let template = warp::path!("email" / "preview" / "templates" / String)
+        .and(warp::get())
+        .and(with_preview.clone())
+        .and_then(template_handler);
+
+    let template_data = warp::path!("email" / "preview" / "templates" / String)
+        .and(warp::post())
+        .and(warp::body::json::<Value>())
+        .and(with_preview.clone())
+        .and_then(template_data_handler);
+
+    let sent = warp::path!("email" / "preview" / "sent" / usize)
+        .and(warp::get())
+        .and(with_preview)
+        .and_then(sent_handler);
+
+    index.or(template).or(template_data).or(sent)
+}
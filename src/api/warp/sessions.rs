@@ -17,7 +17,7 @@ use warp_sessions::{
 use crate::oidc::{IdentityProvider, OidcToken};
 
 use super::{AnyhowError, RejectReason};
-use crate::api::{AuthRejectReason, AuthenticatedUser, ValidatesIdentity};
+use crate::api::{magic, AuthRejectReason, AuthenticatedUser, ValidatesIdentity};
 
 impl AuthRejectReason {
     fn into_rejection(self) -> Rejection {
@@ -251,6 +251,18 @@ pub fn authenticate(
                   mut session: SessionWithStore<MemoryStore>| {
                 let idp = idp.clone();
                 async move {
+                    // Sessions from magic links are signed by us and don't need the identity
+                    // provider.
+                    let magic_user = match (&bearer, &token) {
+                        (None, Some(token)) => magic::authenticate_cookie(token),
+                        _ => None,
+                    };
+                    if let Some(magic_user) = magic_user {
+                        let auth_user = magic_user.map_err(|err| {
+                            AuthRejectReason::invalid_session_token(format!("magic: {}", err))
+                        })?;
+                        return Ok((auth_user, session));
+                    }
                     if let Some(idp) = idp {
                         // Prefer the bearer token
                         let token = match bearer {
@@ -387,10 +399,15 @@ async fn logout_handler(
     session: SessionWithStore<MemoryStore>,
     token: String,
 ) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
-    let token = parse_auth_cookie(&token)
-        .map_err(|err| AuthRejectReason::invalid_session_token(format!("{:?}", err)))?;
-    let logout_url = idp.logout_oidc("/", &token);
-    let uri = logout_url.as_str().parse::<warp::http::Uri>().unwrap();
+    // Magic link sessions never went through the identity provider.
+    let uri = if magic::MagicToken::parse(&token).is_some() {
+        warp::http::Uri::from_static("/")
+    } else {
+        let token = parse_auth_cookie(&token)
+            .map_err(|err| AuthRejectReason::invalid_session_token(format!("{:?}", err)))?;
+        let logout_url = idp.logout_oidc("/", &token);
+        logout_url.as_str().parse::<warp::http::Uri>().unwrap()
+    };
 
     let reply = warp::redirect(uri);
     let mut response = reply.into_response();
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+
+use futures_util::{Stream, TryStreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::hyper::body::Buf;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::with_db;
+use crate::api::{AnyhowError, RejectReason};
+use crate::email::suppression::{record_webhook, WebhookProvider, WebhookSecret};
+use crate::tables::DbPool;
+
+/// The largest webhook body read. Larger requests are refused before the token is checked.
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+#[derive(Deserialize)]
+struct WebhookQuery {
+    token: String,
+}
+
+async fn webhook_handler(
+    provider: String,
+    query: WebhookQuery,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+    db_pool: Arc<DbPool>,
+    secret: Arc<WebhookSecret>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if !secret.verify(&query.token) {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&json!({"message": "denied"})),
+            StatusCode::FORBIDDEN,
+        )));
+    }
+    let provider: WebhookProvider = provider
+        .parse()
+        .map_err(|_| RejectReason::not_found(format!("Webhook {}", provider)))?;
+    // Only read the body once the token is known to be good.
+    let body = body
+        .try_fold(vec![], |mut bytes, mut chunk| async move {
+            while chunk.has_remaining() {
+                let read = chunk.chunk();
+                bytes.extend_from_slice(read);
+                let len = read.len();
+                chunk.advance(len);
+            }
+            Ok(bytes)
+        })
+        .await
+        .map_err(|err| RejectReason::bad_request(format!("Invalid webhook body: {}", err)))?;
+    let body: Value = serde_json::from_slice(&body)
+        .map_err(|err| RejectReason::bad_request(format!("Invalid webhook body: {}", err)))?;
+    let mut conn = db_pool.get().await.map_err(RejectReason::pool_error)?;
+    let suppressed = record_webhook(&mut conn, provider, &body)
+        .await
+        .map_err(AnyhowError::from)?;
+    Ok(Box::new(warp::reply::json(
+        &json!({"suppressed": suppressed}),
+    )))
+}
+
+/// Bounce and complaint notifications. Point the provider at
+/// `POST /email/webhooks/{ses,sendgrid,postmark}?token=<secret>`. Bodies are read as JSON
+/// whatever their content type, since SNS posts it as `text/plain`, and are limited to 1 MiB.
+pub fn routes(
+    pool: Arc<DbPool>,
+    secret: Arc<WebhookSecret>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("email" / "webhooks" / String)
+        .and(warp::post())
+        .and(warp::query::<WebhookQuery>())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::stream())
+        .and(with_db(pool))
+        .and(warp::any().map(move || secret.clone()))
+        .and_then(webhook_handler)
+}
+
+#[cfg(test)]
+mod test {
+    use function_name::named;
+
+    use super::*;
+    use crate::tables::establish_connection_pool;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[tokio::test]
+    #[named]
+    async fn test_sns_webhook_route() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let pool = establish_connection_pool(&harness.db_conf.db_url(&harness.db_name), false)
+            .await
+            .expect("pool");
+        let secret = "s".repeat(32);
+        let filter = routes(
+            Arc::new(pool),
+            Arc::new(WebhookSecret::new(&secret).unwrap()),
+        );
+
+        let bounce = json!({
+            "notificationType": "Bounce",
+            "bounce": {
+                "bounceType": "Permanent",
+                "bouncedRecipients": [{"emailAddress": "gone@example.com"}],
+            },
+        });
+        let sns = json!({"Type": "Notification", "Message": bounce.to_string()});
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/email/webhooks/ses?token={}", secret))
+            .header("content-type", "text/plain; charset=UTF-8")
+            .body(sns.to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<Value>(response.body()).unwrap(),
+            json!({"suppressed": 1})
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/email/webhooks/ses?token=wrong")
+            .body(sns.to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/email/webhooks/ses?token={}", secret))
+            .body(vec![b' '; MAX_BODY_BYTES as usize + 1])
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
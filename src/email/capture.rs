@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use super::transport::{EmailTransport, TransportError};
+use super::Email;
+
+/// Records sent emails instead of delivering them, for asserting on in tests. Clones share
+/// the same buffer, so keep one and give another to `run_transport`.
+#[derive(Clone, Default)]
+pub struct CaptureTransport {
+    emails: Arc<Mutex<Vec<Email>>>,
+    sent: Arc<Notify>,
+}
+
+impl CaptureTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every captured email, oldest first.
+    pub fn emails(&self) -> Vec<Email> {
+        self.emails.lock().expect("capture lock poisoned").clone()
+    }
+
+    fn filter(&self, matches: impl Fn(&Email) -> bool) -> Vec<Email> {
+        let emails = self.emails.lock().expect("capture lock poisoned");
+        emails
+            .iter()
+            .filter(|email| matches(email))
+            .cloned()
+            .collect()
+    }
+
+    /// The emails delivered to `address`, as recipient, CC or BCC.
+    pub fn to(&self, address: &str) -> Vec<Email> {
+        self.filter(|email| is_recipient(email, address))
+    }
+
+    /// The emails whose subject contains `subject`.
+    pub fn with_subject(&self, subject: &str) -> Vec<Email> {
+        self.filter(|email| email.subject.contains(subject))
+    }
+
+    /// The most recent email delivered to `address`.
+    pub fn last_to(&self, address: &str) -> Option<Email> {
+        self.to(address).pop()
+    }
+
+    pub fn clear(&self) {
+        self.emails.lock().expect("capture lock poisoned").clear();
+    }
+
+    /// Wait for an email to `address`, which is sent asynchronously by the scheduler. Returns
+    /// the most recent one, or `None` if none arrives within `wait`.
+    pub async fn wait_for(&self, address: &str, wait: Duration) -> Option<Email> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let sent = self.sent.notified();
+            tokio::pin!(sent);
+            sent.as_mut().enable();
+            if let Some(email) = self.last_to(address) {
+                return Some(email);
+            }
+            timeout_at(deadline, sent).await.ok()?;
+        }
+    }
+}
+
+impl EmailTransport for CaptureTransport {
+    async fn send(&mut self, email: &Email) -> Result<(), TransportError> {
+        self.emails
+            .lock()
+            .expect("capture lock poisoned")
+            .push(email.clone());
+        self.sent.notify_waiters();
+        Ok(())
+    }
+}
+
+fn is_recipient(email: &Email, address: &str) -> bool {
+    email
+        .recipients()
+        .any(|to| to.as_str().eq_ignore_ascii_case(address))
+}
+
+/// The http(s) links in the HTML and text of `email`, in order of appearance and without
+/// duplicates. HTML escapes such as `&amp;` are decoded.
+pub fn links(email: &Email) -> Vec<String> {
+    let mut links: Vec<String> = vec![];
+    for body in [
+        email.message.as_ref(),
+        email.text.as_deref().unwrap_or_default(),
+    ] {
+        let mut rest = body;
+        while let Some(start) = ["http://", "https://"]
+            .iter()
+            .filter_map(|scheme| rest.find(scheme))
+            .min()
+        {
+            let link = &rest[start..];
+            let end = link
+                .find(|c: char| c.is_whitespace() || "\"'<>()[]".contains(c))
+                .unwrap_or(link.len());
+            let decoded = unescape_html(link[..end].trim_end_matches(['.', ',', ';', '!', '?']));
+            if !links.contains(&decoded) {
+                links.push(decoded);
+            }
+            rest = &link[end..];
+        }
+    }
+    links
+}
+
+/// Undo the escapes Handlebars applies to `{{expressions}}`.
+fn unescape_html(s: &str) -> String {
+    s.replace("&#x3D;", "=")
+        .replace("&#x27;", "'")
+        .replace("&#x60;", "`")
+        .replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::email::test::email;
+    use crate::email::FilledTemplate;
+
+    #[tokio::test]
+    async fn test_capture_transport() {
+        let capture = CaptureTransport::new();
+        let mut transport = capture.clone();
+        let waiting = tokio::spawn({
+            let capture = capture.clone();
+            async move {
+                capture
+                    .wait_for("grace@example.com", Duration::from_secs(5))
+                    .await
+            }
+        });
+
+        let html = "<a href=\"https://example.com/verify?token&#x3D;abc&amp;next&#x3D;%2F\">\
+                    Verify</a> or visit https://example.com/help.";
+        transport
+            .send(&email("Ada@example.com", "Verify your email", html))
+            .await
+            .unwrap();
+        let text = email("grace@example.com", "Sign in", "<p>Sign in</p>").with_text(
+            FilledTemplate("Open http://localhost/magic?t=1".to_string()),
+        );
+        transport.send(&text).await.unwrap();
+
+        let received = waiting.await.unwrap().expect("captured");
+        assert_eq!(received.subject, "Sign in");
+        assert_eq!(links(&received), vec!["http://localhost/magic?t=1"]);
+        assert_eq!(capture.emails().len(), 2);
+        assert_eq!(capture.with_subject("Verify").len(), 1);
+        let verify = capture.last_to("ada@example.com").expect("captured");
+        assert_eq!(
+            links(&verify),
+            vec![
+                "https://example.com/verify?token=abc&next=%2F",
+                "https://example.com/help"
+            ]
+        );
+        assert!(capture.to("nobody@example.com").is_empty());
+
+        capture.clear();
+        assert!(capture
+            .wait_for("ada@example.com", Duration::from_millis(10))
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,168 @@
+use chrono::{DateTime, Utc};
+use diesel_async::AsyncPgConnection;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{sleep, Duration};
+
+use super::{EmailTemplate, ScheduledEmail};
+use crate::router::ChannelRouter;
+use crate::tables::OutboxEmail;
+
+/// Announce on the `ChannelRouter` to cancel delayed emails held by the scheduler.
+#[derive(Clone, Debug)]
+pub struct CancelScheduledEmail {
+    pub cancel_key: String,
+}
+
+/// Cancel every pending email with `cancel_key`, both in the durable outbox and held in memory
+/// by schedulers listening on `router`. Returns how many outbox emails were cancelled.
+pub async fn cancel_scheduled_email(
+    conn: &mut AsyncPgConnection,
+    router: &ChannelRouter,
+    cancel_key: &str,
+) -> anyhow::Result<usize> {
+    let cancelled = OutboxEmail::cancel(conn, cancel_key).await?;
+    router
+        .announce()
+        .send(CancelScheduledEmail {
+            cancel_key: cancel_key.to_string(),
+        })
+        .ok();
+    Ok(cancelled)
+}
+
+/// Emails the scheduler holds until their `send_at`. These are lost on restart, so enqueue
+/// emails to the outbox when they must survive one.
+pub(super) struct DelayedEmails<T: EmailTemplate + 'static> {
+    emails: Vec<(DateTime<Utc>, ScheduledEmail<T>)>,
+}
+
+impl<T: EmailTemplate + 'static> DelayedEmails<T> {
+    pub fn new() -> Self {
+        Self { emails: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.emails.len()
+    }
+
+    /// Hold `email` if it isn't due yet, otherwise give it back.
+    pub fn hold(&mut self, email: ScheduledEmail<T>) -> Option<ScheduledEmail<T>> {
+        match email.send_at {
+            Some(send_at) if send_at > Utc::now() => {
+                self.emails.push((send_at, email));
+                None
+            }
+            _ => Some(email),
+        }
+    }
+
+    pub fn cancel(&mut self, cancel_key: &str) {
+        let held = self.emails.len();
+        self.emails
+            .retain(|(_, email)| email.cancel_key.as_deref() != Some(cancel_key));
+        if self.emails.len() < held {
+            tracing::info!("Cancelled delayed email {}", cancel_key);
+        }
+    }
+
+    /// When the next email is due.
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.emails.iter().map(|(send_at, _)| *send_at).min()
+    }
+
+    /// Release the emails which are due, earliest first.
+    pub fn take_due(&mut self) -> Vec<ScheduledEmail<T>> {
+        let now = Utc::now();
+        let (mut due, held) = self
+            .emails
+            .drain(..)
+            .partition(|(send_at, _)| *send_at <= now);
+        self.emails = held;
+        due.sort_by_key(|(send_at, _)| *send_at);
+        due.into_iter().map(|(_, email)| email).collect()
+    }
+}
+
+/// Wait until `due`, forever if there is nothing due.
+pub(super) async fn sleep_until_due(due: Option<DateTime<Utc>>) {
+    match due {
+        Some(due) => sleep((due - Utc::now()).to_std().unwrap_or(Duration::ZERO)).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next cancellation, or never without a receiver.
+pub(super) async fn next_cancel(
+    rx: &mut Option<broadcast::Receiver<CancelScheduledEmail>>,
+) -> String {
+    let Some(rx) = rx else {
+        return std::future::pending().await;
+    };
+    loop {
+        match rx.recv().await {
+            Ok(cancel) => return cancel.cancel_key,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("Missed {} delayed email cancellations", skipped);
+            }
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use email_address::EmailAddress;
+    use handlebars::Handlebars;
+
+    use super::*;
+    use crate::email::FilledTemplate;
+
+    #[derive(Clone, Debug)]
+    struct Reminder(&'static str);
+
+    impl EmailTemplate for Reminder {
+        fn subject(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn fill(self, _handlebars: &Handlebars) -> anyhow::Result<FilledTemplate> {
+            Ok(FilledTemplate(self.0.to_string()))
+        }
+    }
+
+    fn reminder(subject: &'static str, in_ms: i64) -> ScheduledEmail<Reminder> {
+        let to = EmailAddress::from_str("ada@example.com").unwrap();
+        ScheduledEmail::new(to, Reminder(subject))
+            .send_at(Utc::now() + chrono::Duration::milliseconds(in_ms))
+            .cancel_key(subject)
+    }
+
+    #[tokio::test]
+    async fn test_delayed_emails() {
+        let mut delayed = DelayedEmails::new();
+        let now = ScheduledEmail::new(
+            EmailAddress::from_str("ada@example.com").unwrap(),
+            Reminder("now"),
+        );
+        assert!(delayed.hold(now).is_some());
+        assert!(delayed.hold(reminder("past", -10)).is_some());
+        assert!(delayed.hold(reminder("second", 40)).is_none());
+        assert!(delayed.hold(reminder("first", 20)).is_none());
+        assert!(delayed.hold(reminder("cancelled", 30)).is_none());
+        assert!(delayed.take_due().is_empty());
+
+        delayed.cancel("cancelled");
+        sleep_until_due(delayed.next_due()).await;
+        sleep(Duration::from_millis(30)).await;
+        let due: Vec<_> = delayed
+            .take_due()
+            .into_iter()
+            .map(|email| email.template.0)
+            .collect();
+        assert_eq!(due, vec!["first", "second"]);
+        assert_eq!(delayed.len(), 0);
+        assert_eq!(delayed.next_due(), None);
+    }
+}
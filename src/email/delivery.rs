@@ -0,0 +1,309 @@
+use rand::Rng;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+use super::transport::{DeliveryReport, EmailTransport};
+use super::Email;
+use crate::rate_limit::RateLimitedReceiver;
+use crate::router::ChannelRouter;
+
+/// Close an open connection if no email arrives for this long instead of waiting for the
+/// server to.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often and how patiently transient failures are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts per email, including the first.
+    pub max_attempts: u32,
+    /// The delay after the first failure, doubled after each further failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// The delay before the next attempt once `attempts` attempts have failed.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// An email that could not be delivered, announced on the `ChannelRouter`.
+#[derive(Clone, Debug)]
+pub struct DeadLetter {
+    pub email: Email,
+    pub error: String,
+    pub attempts: u32,
+    /// Whether the transport rejected the email outright rather than running out of retries.
+    pub permanent: bool,
+}
+
+struct Retry {
+    due: Instant,
+    email: Email,
+    attempts: u32,
+}
+
+/// Delivers emails from a `RateLimitedReceiver` with an `EmailTransport`, retrying transient
+/// failures with exponential backoff. Emails which fail permanently or run out of attempts are
+/// announced as `DeadLetter`s on the router given to `dead_letters`, and logged otherwise.
+///
+/// Waiting retries don't hold up new emails.
+pub struct Delivery<X: EmailTransport> {
+    transport: X,
+    policy: RetryPolicy,
+    router: Option<ChannelRouter>,
+    reports: Option<broadcast::Sender<DeliveryReport>>,
+}
+
+impl<X: EmailTransport> Delivery<X> {
+    pub fn new(transport: X) -> Self {
+        Self {
+            transport,
+            policy: RetryPolicy::default(),
+            router: None,
+            reports: None,
+        }
+    }
+
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Announce undeliverable emails as `DeadLetter`s on `router`.
+    pub fn dead_letters(mut self, router: ChannelRouter) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    /// Report the final outcome of every email on `reports`.
+    pub fn reports(mut self, reports: broadcast::Sender<DeliveryReport>) -> Self {
+        self.reports = Some(reports);
+        self
+    }
+
+    /// Deliver every email from `rx` until the channel closes and pending retries are done.
+    /// Use this as the `send_email` consumer of `schedule_emails`.
+    pub async fn run(mut self, mut rx: RateLimitedReceiver<Email>) {
+        // Forward through a plain channel so waiting on retries and idle connections never
+        // cancels a rate limited receive midway.
+        let (email_tx, mut email_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(email) = rx.recv().await {
+                if email_tx.send(email).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut retries: Vec<Retry> = vec![];
+        let mut open = true;
+        while open || !retries.is_empty() {
+            let next_retry = retries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, retry)| retry.due)
+                .map(|(i, retry)| (i, retry.due));
+            let connected = self.transport.is_connected();
+            tokio::select! {
+                email = email_rx.recv(), if open => match email {
+                    Some(email) => self.attempt(email, 1, &mut retries).await,
+                    None => open = false,
+                },
+                _ = sleep_until(next_retry.map_or_else(Instant::now, |(_, due)| due)),
+                    if next_retry.is_some() =>
+                {
+                    let (i, _) = next_retry.expect("checked by the branch condition");
+                    let Retry { email, attempts, .. } = retries.swap_remove(i);
+                    self.attempt(email, attempts + 1, &mut retries).await;
+                }
+                _ = sleep(IDLE_TIMEOUT), if connected => self.transport.close().await,
+            }
+        }
+        self.transport.close().await;
+        tracing::info!("Email delivery shutting down");
+    }
+
+    async fn attempt(&mut self, email: Email, attempts: u32, retries: &mut Vec<Retry>) {
+        let err = match self.transport.send(&email).await {
+            Ok(()) => {
+                self.report(&email, attempts, None, false);
+                return;
+            }
+            Err(err) => err,
+        };
+        let permanent = err.is_permanent();
+        if !permanent && attempts < self.policy.max_attempts {
+            let backoff = self.policy.backoff(attempts);
+            // Spread out retries of emails which failed together.
+            let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 10);
+            tracing::warn!(
+                "Email to {} failed on attempt {}, retrying in {:?}: {}",
+                email.to,
+                attempts,
+                backoff,
+                err
+            );
+            retries.push(Retry {
+                due: Instant::now() + backoff + Duration::from_millis(jitter),
+                email,
+                attempts,
+            });
+            return;
+        }
+
+        tracing::error!(
+            "Email to {} failed after {} attempts: {}",
+            email.to,
+            attempts,
+            err
+        );
+        let error = err.to_string();
+        self.report(&email, attempts, Some(error.clone()), permanent);
+        if let Some(router) = &self.router {
+            router
+                .announce()
+                .send(DeadLetter {
+                    email,
+                    error,
+                    attempts,
+                    permanent,
+                })
+                .ok();
+        }
+    }
+
+    fn report(&self, email: &Email, attempts: u32, error: Option<String>, permanent: bool) {
+        if let Some(reports) = &self.reports {
+            reports
+                .send(DeliveryReport {
+                    to: email.to.clone(),
+                    subject: email.subject.clone(),
+                    attempts,
+                    error,
+                    permanent,
+                    outbox_ids: email.outbox_ids.clone(),
+                })
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::email::test::email;
+    use crate::email::transport::TransportError;
+    use crate::rate_limit::{rate_limited_channel, RateLimit, RateLimitProfile};
+
+    /// Fails each recipient with the statuses listed for it, then accepts.
+    struct FlakyTransport {
+        failures: HashMap<String, Vec<u16>>,
+    }
+
+    impl EmailTransport for FlakyTransport {
+        async fn send(&mut self, email: &Email) -> Result<(), TransportError> {
+            match self.failures.get_mut(&email.to.to_string()) {
+                Some(statuses) if !statuses.is_empty() => Err(TransportError::Rejected {
+                    status: statuses.remove(0),
+                    body: String::new(),
+                }),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivery_retries() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(15),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(15));
+
+        let transport = FlakyTransport {
+            failures: HashMap::from([
+                ("flaky@example.com".to_string(), vec![503, 503]),
+                ("down@example.com".to_string(), vec![503, 503, 503]),
+                ("bounce@example.com".to_string(), vec![422]),
+            ]),
+        };
+        let router = ChannelRouter::new();
+        let mut dead_letters = router.subscribe::<DeadLetter>();
+        let (reports_tx, mut reports) = broadcast::channel(10);
+        let (tx, rx) = rate_limited_channel(RateLimitProfile {
+            max_rate: RateLimit {
+                rate_per_window: 10,
+                window: Duration::from_secs(1),
+            },
+            burst_rate: None,
+        });
+        let delivery = Delivery::new(transport)
+            .retry_policy(policy)
+            .dead_letters(router)
+            .reports(reports_tx);
+        let handle = tokio::spawn(delivery.run(rx));
+
+        for to in [
+            "flaky@example.com",
+            "down@example.com",
+            "bounce@example.com",
+            "ok@example.com",
+        ] {
+            tx.send(email(to, "Hello", "<p>Hi</p>")).await.unwrap();
+        }
+        drop(tx);
+        handle.await.unwrap();
+
+        let mut outcomes = HashMap::new();
+        while let Ok(report) = reports.try_recv() {
+            outcomes.insert(
+                report.to.to_string(),
+                (report.attempts, report.error.is_some(), report.permanent),
+            );
+        }
+        assert_eq!(outcomes["ok@example.com"], (1, false, false));
+        assert_eq!(outcomes["flaky@example.com"], (3, false, false));
+        assert_eq!(outcomes["down@example.com"], (3, true, false));
+        assert_eq!(outcomes["bounce@example.com"], (1, true, true));
+
+        let mut dead = vec![];
+        while let Ok(letter) = dead_letters.try_recv() {
+            dead.push((letter.email.to.to_string(), letter.permanent));
+        }
+        dead.sort();
+        assert_eq!(
+            dead,
+            vec![
+                ("bounce@example.com".to_string(), true),
+                ("down@example.com".to_string(), false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use email_address::EmailAddress;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+use uuid::Uuid;
+
+use super::{EmailTemplate, ScheduledEmail};
+use crate::schema::auth::{metadata, users};
+use crate::tables::{DbPool, OutboxEmail};
+
+/// The user metadata key holding a user's `DigestFrequency`.
+pub const DIGEST_METADATA_KEY: &str = "email_digest";
+
+/// A template which can combine several notifications into one email.
+pub trait DigestTemplate: EmailTemplate {
+    /// Combine the notifications collected for one recipient, oldest first. The digest is
+    /// sent with the headers of the first notification, so keep anything else the
+    /// notifications carry, such as their locale, in the combined template.
+    fn digest(notifications: Vec<Self>) -> Self;
+}
+
+/// How often a user wants digestable notifications, stored in their metadata under
+/// `email_digest` as e.g. `"daily"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestFrequency {
+    /// Send each notification on its own.
+    Immediate,
+    Hourly,
+    Daily,
+    Weekly,
+    /// Drop digestable notifications.
+    Never,
+}
+
+impl DigestFrequency {
+    /// How long notifications are collected, if they are sent at all.
+    pub fn window(&self) -> Option<Duration> {
+        let hours = match self {
+            Self::Immediate => return Some(Duration::ZERO),
+            Self::Hourly => 1,
+            Self::Daily => 24,
+            Self::Weekly => 7 * 24,
+            Self::Never => return None,
+        };
+        Some(Duration::from_secs(hours * 60 * 60))
+    }
+}
+
+impl FromStr for DigestFrequency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediate" => Ok(Self::Immediate),
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            "never" => Ok(Self::Never),
+            _ => anyhow::bail!("Unknown digest frequency: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DigestProfile {
+    /// How long notifications are collected for users without a frequency setting.
+    pub window: Duration,
+}
+
+impl Default for DigestProfile {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+struct Batch<T: EmailTemplate + 'static> {
+    due: Instant,
+    /// When the batch is due, to hold its outbox rows until then.
+    held_until: chrono::NaiveDateTime,
+    emails: Vec<ScheduledEmail<T>>,
+}
+
+/// Collects the digestable emails from `rx` per recipient and sends each recipient's batch to
+/// `tx` as one `DigestTemplate::digest` email when its window ends. Other emails are passed
+/// through right away, so put this stage between the producers and `schedule_emails`.
+///
+/// Batches are kept in memory and sent early when `rx` closes. Emails from `dispatch_outbox`
+/// are held in the outbox until their batch is due, so they aren't dispatched again while
+/// they wait, and are cancelled there if the recipient doesn't want them.
+pub fn digest_emails<T>(
+    pool: Arc<DbPool>,
+    mut rx: broadcast::Receiver<ScheduledEmail<T>>,
+    tx: broadcast::Sender<ScheduledEmail<T>>,
+    profile: DigestProfile,
+) -> JoinHandle<()>
+where
+    T: DigestTemplate + 'static,
+{
+    tokio::spawn(async move {
+        let mut batches: HashMap<String, Batch<T>> = HashMap::new();
+        loop {
+            let next_due = batches.values().map(|batch| batch.due).min();
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Ok(email) if email.digest => {
+                        collect(&pool, &mut batches, &tx, email, profile).await;
+                    }
+                    Ok(email) => forward(&tx, email),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Digest stage missed {} emails", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                    let now = Instant::now();
+                    let due: Vec<_> = batches
+                        .iter()
+                        .filter(|(_, batch)| batch.due <= now)
+                        .map(|(recipient, _)| recipient.clone())
+                        .collect();
+                    for recipient in due {
+                        if let Some(batch) = batches.remove(&recipient) {
+                            send_digest(&tx, batch.emails);
+                        }
+                    }
+                }
+            }
+        }
+        for (_, batch) in batches.drain() {
+            send_digest(&tx, batch.emails);
+        }
+        tracing::info!("Digest stage shutting down");
+    })
+}
+
+async fn collect<T: DigestTemplate>(
+    pool: &DbPool,
+    batches: &mut HashMap<String, Batch<T>>,
+    tx: &broadcast::Sender<ScheduledEmail<T>>,
+    email: ScheduledEmail<T>,
+    profile: DigestProfile,
+) {
+    let recipient = email.to.as_str().to_lowercase();
+    if let Some(batch) = batches.get_mut(&recipient) {
+        let collected = |id| batch.emails.iter().any(|e| e.outbox_ids.contains(id));
+        if !email.outbox_ids.is_empty() && email.outbox_ids.iter().all(collected) {
+            return;
+        }
+        hold_outbox(pool, &email.outbox_ids, batch.held_until).await;
+        batch.emails.push(email);
+        return;
+    }
+    let window = match digest_frequency(pool, &email.to).await {
+        Some(frequency) => frequency.window(),
+        None => Some(profile.window),
+    };
+    match window {
+        None => {
+            tracing::debug!("{} doesn't want notification emails", email.to);
+            cancel_outbox(pool, &email.outbox_ids).await;
+        }
+        Some(Duration::ZERO) => forward(tx, email),
+        Some(window) => {
+            let held_until = chrono::Duration::from_std(window)
+                .ok()
+                .and_then(|window| chrono::Utc::now().naive_utc().checked_add_signed(window))
+                .unwrap_or(chrono::NaiveDateTime::MAX);
+            hold_outbox(pool, &email.outbox_ids, held_until).await;
+            batches.insert(
+                recipient,
+                Batch {
+                    due: Instant::now() + window,
+                    held_until,
+                    emails: vec![email],
+                },
+            );
+        }
+    }
+}
+
+async fn hold_outbox(pool: &DbPool, ids: &[Uuid], until: chrono::NaiveDateTime) {
+    if ids.is_empty() {
+        return;
+    }
+    let held = match pool.get().await {
+        Ok(mut conn) => OutboxEmail::hold(&mut conn, ids, until).await,
+        Err(err) => {
+            tracing::error!("Digest stage could not get a connection: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = held {
+        tracing::error!("Could not hold outbox emails for their digest: {}", err);
+    }
+}
+
+async fn cancel_outbox(pool: &DbPool, ids: &[Uuid]) {
+    if ids.is_empty() {
+        return;
+    }
+    let cancelled = match pool.get().await {
+        Ok(mut conn) => OutboxEmail::cancel_sending(&mut conn, ids).await,
+        Err(err) => {
+            tracing::error!("Digest stage could not get a connection: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = cancelled {
+        tracing::error!("Could not cancel unwanted outbox emails: {}", err);
+    }
+}
+
+fn send_digest<T: DigestTemplate>(
+    tx: &broadcast::Sender<ScheduledEmail<T>>,
+    mut emails: Vec<ScheduledEmail<T>>,
+) {
+    let email = if emails.len() == 1 {
+        emails.remove(0)
+    } else {
+        let to = emails[0].to.clone();
+        let headers = emails[0].headers.clone();
+        let transactional = emails[0].transactional;
+        let outbox_ids = emails
+            .iter()
+            .flat_map(|email| email.outbox_ids.iter().copied())
+            .collect();
+        let templates = emails.into_iter().map(|email| email.template).collect();
+        let mut digest = ScheduledEmail::new(to, T::digest(templates)).with_headers(headers);
+        digest.transactional = transactional;
+        digest.outbox_ids = outbox_ids;
+        digest
+    };
+    forward(tx, email);
+}
+
+fn forward<T: EmailTemplate>(
+    tx: &broadcast::Sender<ScheduledEmail<T>>,
+    mut email: ScheduledEmail<T>,
+) {
+    email.digest = false;
+    if tx.send(email).is_err() {
+        tracing::warn!("No email scheduler is running, notification dropped");
+    }
+}
+
+/// The digest frequency in the metadata of the user with address `to`.
+async fn digest_frequency(pool: &DbPool, to: &EmailAddress) -> Option<DigestFrequency> {
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::error!("Digest stage could not get a connection: {}", err);
+            return None;
+        }
+    };
+    let data = metadata::table
+        .inner_join(users::table)
+        .filter(users::email.eq(to.as_str()))
+        .select(metadata::data)
+        .first::<serde_json::Value>(&mut conn)
+        .await
+        .optional()
+        .unwrap_or_else(|err| {
+            tracing::warn!("Could not read the digest frequency of {}: {}", to, err);
+            None
+        })?;
+    let frequency = data.get(DIGEST_METADATA_KEY)?.as_str()?;
+    frequency
+        .parse()
+        .inspect_err(|err| tracing::warn!("{} for {}", err, to))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use diesel_async::AsyncPgConnection;
+    use function_name::named;
+    use handlebars::Handlebars;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use tokio::time::timeout;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::email::headers::EmailHeaders;
+    use crate::email::FilledTemplate;
+    use crate::tables::establish_connection_pool;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Activity(Vec<String>);
+
+    impl EmailTemplate for Activity {
+        fn subject(&self) -> String {
+            self.0.join(", ")
+        }
+
+        fn fill(self, _handlebars: &Handlebars) -> anyhow::Result<FilledTemplate> {
+            Ok(FilledTemplate(self.subject()))
+        }
+    }
+
+    impl DigestTemplate for Activity {
+        fn digest(notifications: Vec<Self>) -> Self {
+            Self(notifications.into_iter().flat_map(|n| n.0).collect())
+        }
+    }
+
+    fn notify(to: &str, what: &str) -> ScheduledEmail<Activity> {
+        let to = EmailAddress::from_str(to).expect("valid email");
+        ScheduledEmail::new(to, Activity(vec![what.to_string()])).digestable()
+    }
+
+    async fn add_user(conn: &mut AsyncPgConnection, email: &str, frequency: &str) {
+        let user_id = Uuid::new_v4();
+        diesel::insert_into(users::table)
+            .values((
+                users::id.eq(user_id),
+                users::email.eq(email),
+                users::created.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .await
+            .expect("user");
+        diesel::insert_into(metadata::table)
+            .values((
+                metadata::user_id.eq(user_id),
+                metadata::data.eq(json!({ DIGEST_METADATA_KEY: frequency })),
+            ))
+            .execute(conn)
+            .await
+            .expect("metadata");
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_digest_emails() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+        add_user(&mut conn, "never@example.com", "never").await;
+        add_user(&mut conn, "now@example.com", "immediate").await;
+        let pool = establish_connection_pool(&harness.db_conf.db_url(&harness.db_name), false)
+            .await
+            .expect("pool");
+
+        let (in_tx, in_rx) = broadcast::channel(10);
+        let (out_tx, mut out_rx) = broadcast::channel(10);
+        let profile = DigestProfile {
+            window: Duration::from_millis(100),
+        };
+        let handle = digest_emails(Arc::new(pool), in_rx, out_tx, profile);
+
+        in_tx.send(notify("never@example.com", "dropped")).unwrap();
+        let liked = notify("ada@example.com", "liked").with_headers(
+            EmailHeaders::default().reply_to(EmailAddress::from_str("n@example.com").unwrap()),
+        );
+        in_tx.send(liked).unwrap();
+        in_tx.send(notify("now@example.com", "now")).unwrap();
+        let mut direct = notify("ada@example.com", "direct");
+        direct.digest = false;
+        in_tx.send(direct).unwrap();
+        in_tx.send(notify("Ada@example.com", "replied")).unwrap();
+
+        let mut received = vec![];
+        for _ in 0..3 {
+            let email = timeout(Duration::from_secs(5), out_rx.recv())
+                .await
+                .expect("sent")
+                .expect("open");
+            assert!(!email.digest);
+            received.push((
+                email.to.to_string(),
+                email.template.subject(),
+                email.headers.reply_to.is_some(),
+            ));
+        }
+        assert_eq!(
+            received,
+            vec![
+                ("now@example.com".to_string(), "now".to_string(), false),
+                ("ada@example.com".to_string(), "direct".to_string(), false),
+                (
+                    "ada@example.com".to_string(),
+                    "liked, replied".to_string(),
+                    true
+                ),
+            ]
+        );
+        drop(in_tx);
+        handle.await.expect("stopped");
+        assert!(out_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_digest_outbox() {
+        use crate::email::transport::DeliveryReport;
+        use crate::email::{dispatch_outbox, OutboxProfile};
+        use crate::schema::auth::email_outbox;
+
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+        add_user(&mut conn, "never@example.com", "never").await;
+        let mut queued = vec![];
+        for (to, what) in [
+            ("ada@example.com", "liked"),
+            ("ada@example.com", "replied"),
+            ("never@example.com", "dropped"),
+        ] {
+            let row = OutboxEmail::enqueue(&mut conn, &notify(to, what))
+                .await
+                .expect("enqueued");
+            queued.push(row.id);
+        }
+        let pool = Arc::new(
+            establish_connection_pool(&harness.db_conf.db_url(&harness.db_name), false)
+                .await
+                .expect("pool"),
+        );
+
+        // The digest window outlasts the ack timeout, the held rows mustn't be sent again.
+        let (schedule_tx, schedule_rx) = broadcast::channel(10);
+        let (out_tx, mut out_rx) = broadcast::channel(10);
+        let (reports_tx, reports_rx) = broadcast::channel(10);
+        let digest = digest_emails(
+            pool.clone(),
+            schedule_rx,
+            out_tx,
+            DigestProfile {
+                window: Duration::from_millis(500),
+            },
+        );
+        let dispatcher = dispatch_outbox::<Activity>(
+            pool,
+            schedule_tx,
+            reports_rx,
+            OutboxProfile {
+                interval: Duration::from_millis(10),
+                ack_timeout: Duration::from_millis(200),
+                ..OutboxProfile::default()
+            },
+        );
+
+        let email = timeout(Duration::from_secs(5), out_rx.recv())
+            .await
+            .expect("sent")
+            .expect("open");
+        assert_eq!(email.template.subject(), "liked, replied");
+        let mut outbox_ids = email.outbox_ids.clone();
+        outbox_ids.sort();
+        let mut expected = queued[..2].to_vec();
+        expected.sort();
+        assert_eq!(outbox_ids, expected);
+        reports_tx
+            .send(DeliveryReport {
+                to: email.to.clone(),
+                subject: email.template.subject(),
+                attempts: 1,
+                error: None,
+                permanent: false,
+                outbox_ids: email.outbox_ids,
+            })
+            .unwrap();
+
+        let mut rows = vec![];
+        for _ in 0..100 {
+            rows = email_outbox::table
+                .filter(email_outbox::id.eq_any(&queued))
+                .select((
+                    email_outbox::recipient,
+                    email_outbox::status,
+                    email_outbox::attempts,
+                ))
+                .order((email_outbox::recipient, email_outbox::created))
+                .load::<(String, String, i32)>(&mut conn)
+                .await
+                .expect("rows");
+            if rows.iter().all(|(_, status, _)| status != "sending") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            rows,
+            vec![
+                ("ada@example.com".to_string(), "sent".to_string(), 1),
+                ("ada@example.com".to_string(), "sent".to_string(), 1),
+                ("never@example.com".to_string(), "cancelled".to_string(), 1),
+            ]
+        );
+        assert!(out_rx.try_recv().is_err());
+        dispatcher.abort();
+        digest.abort();
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::Utc;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::mime::MimeBuilder;
+use super::transport::{EmailTransport, TransportError};
+use super::Email;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    /// One `.eml` file per email, which most mail clients open.
+    #[default]
+    Eml,
+    /// A maildir with `tmp`, `new` and `cur`, which mail clients can use as a mailbox.
+    Maildir,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FileTransportConfig {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub format: FileFormat,
+}
+
+/// Writes emails to files instead of delivering them, for local development.
+pub struct FileTransport {
+    dir: PathBuf,
+    format: FileFormat,
+}
+
+impl FileTransport {
+    pub fn new(config: FileTransportConfig) -> std::io::Result<Self> {
+        match config.format {
+            FileFormat::Eml => fs::create_dir_all(&config.dir)?,
+            FileFormat::Maildir => {
+                for sub in ["tmp", "new", "cur"] {
+                    fs::create_dir_all(config.dir.join(sub))?;
+                }
+            }
+        }
+        Ok(Self {
+            dir: config.dir,
+            format: config.format,
+        })
+    }
+
+    /// Write `email`, returning the path of its file.
+    pub fn write(&self, email: &Email) -> std::io::Result<PathBuf> {
+        let message = MimeBuilder::new(email).build();
+        let now = Utc::now();
+        match self.format {
+            FileFormat::Eml => {
+                let path = self.dir.join(format!(
+                    "{}-{}.eml",
+                    now.format("%Y%m%dT%H%M%S%.6f"),
+                    Uuid::new_v4()
+                ));
+                fs::write(&path, message)?;
+                Ok(path)
+            }
+            FileFormat::Maildir => {
+                // Written to tmp then moved so readers never see a partial message.
+                let name = format!(
+                    "{}.M{}P{}.{}",
+                    now.timestamp(),
+                    now.timestamp_subsec_micros(),
+                    std::process::id(),
+                    Uuid::new_v4().simple()
+                );
+                let tmp = self.dir.join("tmp").join(&name);
+                let path = self.dir.join("new").join(&name);
+                fs::write(&tmp, message)?;
+                fs::rename(&tmp, &path)?;
+                Ok(path)
+            }
+        }
+    }
+}
+
+impl EmailTransport for FileTransport {
+    async fn send(&mut self, email: &Email) -> Result<(), TransportError> {
+        let path = self.write(email).map_err(TransportError::Io)?;
+        tracing::info!("Wrote email to {} at {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use email_address::EmailAddress;
+
+    use super::*;
+    use crate::email::FilledTemplate;
+
+    #[tokio::test]
+    async fn test_file_transport() {
+        let dir = std::env::temp_dir().join(format!("emails-{}", Uuid::new_v4()));
+        let to = EmailAddress::from_str("ada@example.com").unwrap();
+        let from = EmailAddress::from_str("noreply@example.com").unwrap();
+        let email = Email::new(&to, &from, "Hello", FilledTemplate("<p>Hi</p>".to_string()));
+
+        let mut eml = FileTransport::new(FileTransportConfig {
+            dir: dir.join("eml"),
+            format: FileFormat::Eml,
+        })
+        .unwrap();
+        eml.send(&email).await.unwrap();
+        let path = eml.write(&email).unwrap();
+        assert_eq!(path.extension().unwrap(), "eml");
+        let message = fs::read_to_string(path).unwrap();
+        assert!(message.contains("Subject: Hello\r\n"), "{}", message);
+        assert_eq!(fs::read_dir(dir.join("eml")).unwrap().count(), 2);
+
+        let maildir = FileTransport::new(FileTransportConfig {
+            dir: dir.join("maildir"),
+            format: FileFormat::Maildir,
+        })
+        .unwrap();
+        let path = maildir.write(&email).unwrap();
+        assert_eq!(path.parent().unwrap(), dir.join("maildir").join("new"));
+        let message = fs::read_to_string(path).unwrap();
+        assert!(message.contains("Subject: Hello\r\n"), "{}", message);
+        assert_eq!(fs::read_dir(dir.join("maildir/tmp")).unwrap().count(), 0);
+        fs::remove_dir_all(dir).ok();
+    }
+}
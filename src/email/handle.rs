@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Counters kept by a running email scheduler.
+#[derive(Debug, Default)]
+pub struct SchedulerMetrics {
+    received: AtomicU64,
+    skipped: AtomicU64,
+    rendered: AtomicU64,
+}
+
+impl SchedulerMetrics {
+    /// Emails received from the schedule channel.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Emails lost because the scheduler fell behind the schedule channel's capacity.
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Emails rendered and handed to the rate limiter.
+    pub fn rendered(&self) -> u64 {
+        self.rendered.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn add_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_skipped(&self, skipped: u64) {
+        self.skipped.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_rendered(&self) {
+        self.rendered.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Controls a spawned `EmailScheduler`. Dropping the handle leaves the scheduler running
+/// until its schedule channel closes.
+pub struct SchedulerHandle {
+    pub(super) shutdown: Arc<Notify>,
+    pub(super) metrics: Arc<SchedulerMetrics>,
+    pub(super) scheduler: JoinHandle<()>,
+    pub(super) sender: JoinHandle<()>,
+}
+
+impl SchedulerHandle {
+    pub fn metrics(&self) -> Arc<SchedulerMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Stop receiving, send the emails already scheduled through the rate limiter and wait
+    /// for `send_email` to finish. Delayed emails which aren't due yet are dropped.
+    pub async fn shutdown(self) {
+        self.shutdown.notify_one();
+        self.wait().await
+    }
+
+    /// Wait for the scheduler to stop on its own, once its schedule channel closes, and for
+    /// `send_email` to finish with the remaining emails.
+    pub async fn wait(self) {
+        if let Err(err) = self.scheduler.await {
+            tracing::error!("Email scheduler failed: {}", err);
+        }
+        if let Err(err) = self.sender.await {
+            tracing::error!("Email sender failed: {}", err);
+        }
+    }
+}
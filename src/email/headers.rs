@@ -0,0 +1,143 @@
+use email_address::EmailAddress;
+use serde::{Deserialize, Serialize};
+
+/// Headers set by the library itself, which custom headers may not override.
+const RESERVED_HEADERS: &[&str] = &[
+    "bcc",
+    "cc",
+    "content-transfer-encoding",
+    "content-type",
+    "date",
+    "from",
+    "list-unsubscribe",
+    "list-unsubscribe-post",
+    "message-id",
+    "mime-version",
+    "reply-to",
+    "subject",
+    "to",
+];
+
+/// How a recipient unsubscribes from bulk mail. Mailbox providers show an unsubscribe button
+/// for these, and require the one-click URL of RFC 8058 from bulk senders.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ListUnsubscribe {
+    /// An https URL which unsubscribes the recipient when it receives a POST of
+    /// `List-Unsubscribe=One-Click`, without any further confirmation.
+    pub one_click_url: Option<String>,
+    pub mailto: Option<EmailAddress>,
+}
+
+impl ListUnsubscribe {
+    pub fn one_click(url: &str) -> Self {
+        Self {
+            one_click_url: Some(url.to_string()),
+            mailto: None,
+        }
+    }
+
+    pub fn mailto(mut self, address: EmailAddress) -> Self {
+        self.mailto = Some(address);
+        self
+    }
+
+    /// The `List-Unsubscribe` and `List-Unsubscribe-Post` header values.
+    fn headers(&self) -> Vec<(String, String)> {
+        let mut targets = vec![];
+        let one_click = self
+            .one_click_url
+            .as_ref()
+            .filter(|url| url.starts_with("https://"));
+        if let Some(url) = one_click {
+            targets.push(format!("<{}>", url));
+        }
+        if let Some(mailto) = &self.mailto {
+            targets.push(format!("<mailto:{}?subject=unsubscribe>", mailto));
+        }
+        let mut headers = vec![];
+        if !targets.is_empty() {
+            headers.push(("List-Unsubscribe".to_string(), targets.join(", ")));
+        }
+        if one_click.is_some() {
+            headers.push((
+                "List-Unsubscribe-Post".to_string(),
+                "List-Unsubscribe=One-Click".to_string(),
+            ));
+        }
+        headers
+    }
+}
+
+/// Everything about an email's envelope beyond its sender, recipient and subject.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EmailHeaders {
+    pub reply_to: Option<EmailAddress>,
+    #[serde(default)]
+    pub cc: Vec<EmailAddress>,
+    #[serde(default)]
+    pub bcc: Vec<EmailAddress>,
+    pub list_unsubscribe: Option<ListUnsubscribe>,
+    /// Extra headers such as `X-Entity-Ref-ID`. Names the library sets itself are ignored.
+    #[serde(default)]
+    pub custom: Vec<(String, String)>,
+}
+
+impl EmailHeaders {
+    pub fn reply_to(mut self, address: EmailAddress) -> Self {
+        self.reply_to = Some(address);
+        self
+    }
+
+    pub fn cc(mut self, address: EmailAddress) -> Self {
+        self.cc.push(address);
+        self
+    }
+
+    pub fn bcc(mut self, address: EmailAddress) -> Self {
+        self.bcc.push(address);
+        self
+    }
+
+    pub fn list_unsubscribe(mut self, list_unsubscribe: ListUnsubscribe) -> Self {
+        self.list_unsubscribe = Some(list_unsubscribe);
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.custom.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Combine with `other`, whose single-valued fields take precedence.
+    pub fn merge(mut self, other: EmailHeaders) -> Self {
+        self.reply_to = other.reply_to.or(self.reply_to);
+        self.cc.extend(other.cc);
+        self.bcc.extend(other.bcc);
+        self.list_unsubscribe = other.list_unsubscribe.or(self.list_unsubscribe);
+        self.custom.extend(other.custom);
+        self
+    }
+
+    /// The List-Unsubscribe headers followed by the valid custom headers, unencoded.
+    pub fn extra_headers(&self) -> Vec<(String, String)> {
+        let mut headers = self
+            .list_unsubscribe
+            .as_ref()
+            .map(ListUnsubscribe::headers)
+            .unwrap_or_default();
+        for (name, value) in &self.custom {
+            if is_valid_custom_header(name) {
+                headers.push((name.clone(), value.clone()));
+            } else {
+                tracing::warn!("Ignoring email header {:?}", name);
+            }
+        }
+        headers
+    }
+}
+
+fn is_valid_custom_header(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+        && !RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str())
+}
@@ -0,0 +1,288 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, Offset, Utc};
+use chrono_tz::Tz;
+use handlebars::{
+    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError,
+    RenderErrorReason,
+};
+use serde_json::Value;
+
+const DEFAULT_DATE_FORMAT: &str = "%B %-d, %Y";
+
+/// Register the helpers every email template can use:
+///
+/// - `{{format_date sent tz="Europe/Paris" format="%H:%M"}}` formats an RFC 3339 string, date
+///   or unix timestamp in an IANA timezone or a fixed offset like `+02:00`, `UTC` by default.
+/// - `{{absolute_url "/settings"}}` joins a path to the `base_url` of the template data, or to
+///   `base=` when given.
+/// - `{{pluralize count "reply" "replies"}}` picks the word for `count`, adding an `s` to the
+///   singular without a plural.
+/// - `{{currency total "EUR"}}` formats an amount in major units, e.g. `€1,234.50`.
+pub fn register_helpers(handlebars: &mut Handlebars) {
+    handlebars.register_helper("format_date", Box::new(format_date));
+    handlebars.register_helper("absolute_url", Box::new(absolute_url));
+    handlebars.register_helper("pluralize", Box::new(pluralize));
+    handlebars.register_helper("currency", Box::new(currency));
+}
+
+fn param<'a>(h: &'a Helper, name: &'static str, index: usize) -> Result<&'a Value, RenderError> {
+    h.param(index)
+        .map(|param| param.value())
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex(name, index).into())
+}
+
+fn hash_str<'a>(h: &'a Helper, key: &str) -> Option<&'a str> {
+    h.hash_get(key).and_then(|value| value.value().as_str())
+}
+
+fn write_escaped(r: &Handlebars, out: &mut dyn Output, s: &str) -> HelperResult {
+    out.write(&r.get_escape_fn()(s))?;
+    Ok(())
+}
+
+fn other(message: String) -> RenderError {
+    RenderErrorReason::Other(message).into()
+}
+
+fn format_date(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let date = match param(h, "format_date", 0)? {
+        Value::Number(secs) => secs
+            .as_i64()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map(|date| date.fixed_offset()),
+        Value::String(date) => DateTime::parse_from_rfc3339(date).ok().or_else(|| {
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc().fixed_offset())
+        }),
+        _ => None,
+    }
+    .ok_or_else(|| other("format_date expects an RFC 3339 date or a timestamp".to_string()))?;
+    let format = hash_str(h, "format").unwrap_or(DEFAULT_DATE_FORMAT);
+    let formatted = match hash_str(h, "tz") {
+        Some(tz) => match tz.parse::<Tz>() {
+            Ok(tz) => date.with_timezone(&tz).format(format).to_string(),
+            Err(_) => {
+                let offset =
+                    parse_offset(tz).ok_or_else(|| other(format!("Invalid timezone {}", tz)))?;
+                date.with_timezone(&offset).format(format).to_string()
+            }
+        },
+        None => date.with_timezone(&Utc.fix()).format(format).to_string(),
+    };
+    write_escaped(r, out, &formatted)
+}
+
+/// `UTC` or an offset like `+02:00`, `-0530` or `+9`, for a `tz` that isn't an IANA name.
+fn parse_offset(tz: &str) -> Option<FixedOffset> {
+    if tz.eq_ignore_ascii_case("utc") || tz.eq_ignore_ascii_case("z") {
+        return Some(Utc.fix());
+    }
+    let (sign, offset) = match tz.as_bytes().first()? {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match offset.split_once(':') {
+        Some(parts) => parts,
+        None if offset.len() == 4 => offset.split_at(2),
+        None => (offset, "0"),
+    };
+    let seconds = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+    FixedOffset::east_opt(sign * seconds)
+}
+
+fn absolute_url(
+    h: &Helper,
+    r: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let path = param(h, "absolute_url", 0)?
+        .as_str()
+        .ok_or(RenderErrorReason::InvalidParamType("string"))?;
+    if path.starts_with("https://") || path.starts_with("http://") {
+        return write_escaped(r, out, path);
+    }
+    let base = hash_str(h, "base")
+        .or_else(|| ctx.data().get("base_url")?.as_str())
+        .ok_or_else(|| other("absolute_url needs a base_url".to_string()))?;
+    let url = format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    write_escaped(r, out, &url)
+}
+
+fn pluralize(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let count = param(h, "pluralize", 0)?
+        .as_f64()
+        .ok_or(RenderErrorReason::InvalidParamType("number"))?;
+    let singular = param(h, "pluralize", 1)?
+        .as_str()
+        .ok_or(RenderErrorReason::InvalidParamType("string"))?;
+    let word = if count == 1.0 {
+        singular.to_string()
+    } else {
+        match h.param(2).and_then(|plural| plural.value().as_str()) {
+            Some(plural) => plural.to_string(),
+            None => format!("{}s", singular),
+        }
+    };
+    write_escaped(r, out, &word)
+}
+
+fn currency(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let amount = match param(h, "currency", 0)? {
+        Value::Number(amount) => amount.as_f64(),
+        Value::String(amount) => amount.parse().ok(),
+        _ => None,
+    }
+    .ok_or(RenderErrorReason::InvalidParamType("number"))?;
+    let code = param(h, "currency", 1)?
+        .as_str()
+        .ok_or(RenderErrorReason::InvalidParamType("string"))?
+        .to_ascii_uppercase();
+    write_escaped(r, out, &format_currency(amount, &code))
+}
+
+fn format_currency(amount: f64, code: &str) -> String {
+    let (symbol, decimals) = match code {
+        "USD" => ("$", 2),
+        "EUR" => ("€", 2),
+        "GBP" => ("£", 2),
+        "CAD" => ("CA$", 2),
+        "AUD" => ("A$", 2),
+        "INR" => ("₹", 2),
+        "JPY" => ("¥", 0),
+        "KRW" => ("₩", 0),
+        _ => ("", 2),
+    };
+    let formatted = format!("{:.*}", decimals, amount.abs());
+    let (whole, fraction) = formatted.split_at(formatted.find('.').unwrap_or(formatted.len()));
+    let mut grouped = String::new();
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    let sign = if amount < 0.0 && formatted.bytes().any(|b| b.is_ascii_digit() && b != b'0') {
+        "-"
+    } else {
+        ""
+    };
+    if symbol.is_empty() {
+        format!("{}{} {}{}", sign, code, grouped, fraction)
+    } else {
+        format!("{}{}{}{}", sign, symbol, grouped, fraction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::email::setup_handlebars;
+
+    #[test]
+    fn test_helpers_and_layouts() {
+        let dir = std::env::temp_dir().join(format!("templates-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("partials")).expect("partials dir");
+        std::fs::create_dir_all(dir.join("layouts")).expect("layouts dir");
+        std::fs::write(
+            dir.join("layouts/base.html"),
+            "<main>{{> @partial-block}}</main>{{> footer}}",
+        )
+        .expect("written");
+        std::fs::write(
+            dir.join("partials/footer.html"),
+            "<a href=\"{{absolute_url \"/settings\"}}\">Settings</a>",
+        )
+        .expect("written");
+        std::fs::write(dir.join("partials/footer.txt"), "Settings").expect("written");
+        std::fs::write(
+            dir.join("receipt.html"),
+            "{{#> base}}{{count}} {{pluralize count \"item\"}} for {{currency total \"USD\"}} \
+             on {{format_date paid tz=\"America/New_York\"}}{{/base}}",
+        )
+        .expect("written");
+        std::fs::write(dir.join("receipt.txt"), "{{> footer.txt}}").expect("written");
+
+        let handlebars = setup_handlebars(&dir).expect("templates");
+        let data = json!({
+            "base_url": "https://example.com/",
+            "count": 3,
+            "total": 1234.5,
+            "paid": "2026-01-01T03:00:00Z",
+        });
+        assert_eq!(
+            handlebars.render("receipt", &data).unwrap(),
+            "<main>3 items for $1,234.50 on December 31, 2025</main>\
+             <a href=\"https://example.com/settings\">Settings</a>"
+        );
+        assert_eq!(handlebars.render("receipt.txt", &data).unwrap(), "Settings");
+        assert!(!handlebars.has_template("partials/footer"));
+        assert!(!handlebars.has_template("layouts/base"));
+        std::fs::remove_dir_all(dir).ok();
+
+        let render = |template: &str, data: Value| {
+            let mut handlebars = Handlebars::new();
+            register_helpers(&mut handlebars);
+            handlebars.render_template(template, &data)
+        };
+        assert_eq!(
+            render("{{pluralize n \"reply\" \"replies\"}}", json!({"n": 1})).unwrap(),
+            "reply"
+        );
+        assert_eq!(
+            render(
+                "{{format_date 0 tz=\"+05:30\" format=\"%Y-%m-%d %H:%M\"}}",
+                json!({})
+            )
+            .unwrap(),
+            "1970-01-01 05:30"
+        );
+        assert_eq!(
+            render(
+                "{{format_date d tz=\"Europe/Paris\" format=\"%H:%M %Z\"}}",
+                json!({"d": "2026-07-01T12:00:00Z"})
+            )
+            .unwrap(),
+            "14:00 CEST"
+        );
+        assert!(render("{{format_date 0 tz=\"Mars/Olympus\"}}", json!({})).is_err());
+        assert_eq!(
+            render(
+                "{{absolute_url \"a\" base=\"http://localhost\"}}",
+                json!({})
+            )
+            .unwrap(),
+            "http://localhost/a"
+        );
+        assert!(render("{{absolute_url \"a\"}}", json!({})).is_err());
+        assert!(render("{{format_date \"soon\"}}", json!({})).is_err());
+        assert_eq!(format_currency(-1234567.0, "JPY"), "-¥1,234,567");
+        assert_eq!(format_currency(-0.001, "CHF"), "CHF 0.00");
+    }
+}
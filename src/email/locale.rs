@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use handlebars::Handlebars;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::auth::metadata;
+use crate::tables::UserId;
+
+/// The catalog key of the email verification subject.
+pub const VERIFY_EMAIL: &str = "verify_email";
+/// The catalog key of the magic link sign in subject.
+pub const SIGN_IN: &str = "sign_in";
+
+/// The user metadata key holding a user's preferred locale.
+const METADATA_KEY: &str = "locale";
+
+/// A lowercase BCP 47 language tag such as `fr` or `pt-br`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Locale(String);
+
+impl Locale {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// This locale followed by each less specific one, e.g. `pt-br` then `pt`.
+    pub fn fallbacks(&self) -> Vec<&str> {
+        let tag = self.0.as_str();
+        let mut tags = vec![tag];
+        tags.extend(tag.rmatch_indices('-').map(|(i, _)| &tag[..i]));
+        tags
+    }
+
+    /// The most preferred locale of an `Accept-Language` header.
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut best: Option<(f32, Self)> = None;
+        for entry in header.split(',') {
+            let mut parts = entry.split(';');
+            let tag = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+            let (Some(quality), Ok(locale)) = (quality, Self::from_str(tag)) else {
+                continue;
+            };
+            if quality > 0.0 && best.as_ref().is_none_or(|(q, _)| quality > *q) {
+                best = Some((quality, locale));
+            }
+        }
+        best.map(|(_, locale)| locale)
+    }
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self("en".to_string())
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Locale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = !s.is_empty()
+            && s.split('-').all(|subtag| {
+                (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+            })
+            && s.bytes()
+                .take_while(|b| *b != b'-')
+                .all(|b| b.is_ascii_alphabetic());
+        if !valid {
+            anyhow::bail!("Invalid locale: {:?}", s);
+        }
+        Ok(Self(s.to_ascii_lowercase()))
+    }
+}
+
+impl TryFrom<String> for Locale {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Locale> for String {
+    fn from(locale: Locale) -> Self {
+        locale.0
+    }
+}
+
+/// The locale to email a user in: the `locale` in their metadata, else the browser's preferred
+/// language, else the default.
+pub async fn recipient_locale(
+    conn: &mut AsyncPgConnection,
+    user_id: UserId,
+    accept_language: Option<&str>,
+) -> Locale {
+    let preferred = metadata::table
+        .find(user_id)
+        .select(metadata::data)
+        .first::<serde_json::Value>(conn)
+        .await
+        .optional()
+        .unwrap_or_else(|err| {
+            tracing::warn!("Could not read the locale of {}: {}", user_id, err);
+            None
+        })
+        .and_then(|data| data.get(METADATA_KEY)?.as_str()?.parse().ok());
+    preferred
+        .or_else(|| accept_language.and_then(Locale::from_accept_language))
+        .unwrap_or_default()
+}
+
+/// The registered name of `template` + `suffix` in `locale`, e.g. `verify_email.fr` for a
+/// `verify_email.fr.html` template, falling back to less specific locales and then to
+/// `template` + `suffix` itself.
+pub fn resolve_template(
+    handlebars: &Handlebars,
+    template: &str,
+    suffix: &str,
+    locale: &Locale,
+) -> String {
+    locale
+        .fallbacks()
+        .into_iter()
+        .map(|tag| format!("{}.{}{}", template, tag, suffix))
+        .find(|name| handlebars.has_template(name))
+        .unwrap_or_else(|| format!("{}{}", template, suffix))
+}
+
+/// Email subjects keyed by template and locale. Loaded from JSON shaped like
+/// `{"verify_email": {"en": "Verify your email", "fr": "Vérifiez votre adresse email"}}`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct SubjectCatalog {
+    subjects: HashMap<String, HashMap<Locale, String>>,
+}
+
+lazy_static! {
+    static ref BUILTIN_SUBJECTS: SubjectCatalog = SubjectCatalog::default()
+        .with(VERIFY_EMAIL, "en", "Verify your email")
+        .with(VERIFY_EMAIL, "de", "Bestätigen Sie Ihre E-Mail-Adresse")
+        .with(VERIFY_EMAIL, "es", "Verifica tu correo electrónico")
+        .with(VERIFY_EMAIL, "fr", "Vérifiez votre adresse email")
+        .with(SIGN_IN, "en", "Sign in")
+        .with(SIGN_IN, "de", "Anmelden")
+        .with(SIGN_IN, "es", "Iniciar sesión")
+        .with(SIGN_IN, "fr", "Connexion");
+}
+
+impl SubjectCatalog {
+    /// Subjects for the emails this library sends.
+    pub fn builtin() -> &'static Self {
+        &BUILTIN_SUBJECTS
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// Add a subject. Panics if `locale` is invalid, so only use this with literals.
+    pub fn with(mut self, template: &str, locale: &str, subject: &str) -> Self {
+        let locale = locale.parse().expect("valid locale");
+        self.insert(template, locale, subject);
+        self
+    }
+
+    pub fn insert(&mut self, template: &str, locale: Locale, subject: &str) {
+        self.subjects
+            .entry(template.to_string())
+            .or_default()
+            .insert(locale, subject.to_string());
+    }
+
+    /// The subject of `template` in `locale` or a less specific locale, else in the default
+    /// locale.
+    pub fn get(&self, template: &str, locale: &Locale) -> Option<&str> {
+        let subjects = self.subjects.get(template)?;
+        locale
+            .fallbacks()
+            .into_iter()
+            .find_map(|tag| subjects.get(&Locale(tag.to_string())))
+            .or_else(|| subjects.get(&Locale::default()))
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use handlebars::Handlebars;
+
+    use super::*;
+
+    fn locale(tag: &str) -> Locale {
+        tag.parse().expect("valid locale")
+    }
+
+    #[test]
+    fn test_locales() {
+        assert_eq!(locale("pt-BR").fallbacks(), vec!["pt-br", "pt"]);
+        assert!(Locale::from_str("12").is_err());
+        assert!(Locale::from_str("*").is_err());
+        assert_eq!(
+            Locale::from_accept_language("en;q=0.5, fr-CA, de;q=0.9"),
+            Some(locale("fr-ca"))
+        );
+        assert_eq!(Locale::from_accept_language("*, fr;q=0"), None);
+
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string("verify_email", "Verify")
+            .unwrap();
+        handlebars
+            .register_template_string("verify_email.fr", "Vérifiez")
+            .unwrap();
+        handlebars
+            .register_template_string("verify_email.fr.txt", "Vérifiez")
+            .unwrap();
+        assert_eq!(
+            resolve_template(&handlebars, "verify_email", "", &locale("fr-ca")),
+            "verify_email.fr"
+        );
+        assert_eq!(
+            resolve_template(&handlebars, "verify_email", ".txt", &locale("fr")),
+            "verify_email.fr.txt"
+        );
+        assert_eq!(
+            resolve_template(&handlebars, "verify_email", "", &locale("de")),
+            "verify_email"
+        );
+
+        let catalog = SubjectCatalog::builtin();
+        assert_eq!(
+            catalog.get(VERIFY_EMAIL, &locale("fr-ca")),
+            Some("Vérifiez votre adresse email")
+        );
+        assert_eq!(catalog.get(SIGN_IN, &locale("ja")), Some("Sign in"));
+        assert_eq!(catalog.get("newsletter", &locale("en")), None);
+    }
+}
@@ -0,0 +1,482 @@
+use uuid::Uuid;
+
+use super::Email;
+
+/// Encoded lines, excluding the CRLF, are limited to 76 characters.
+const MAX_LINE: usize = 76;
+/// Raw bytes per RFC 2047 encoded word, which keeps a folded header line within 78 characters.
+const MAX_ENCODED_WORD_BYTES: usize = 36;
+
+/// A file sent with an email. Inline attachments are referenced from the HTML as
+/// `cid:<content_id>` instead of being listed as downloads.
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+    pub content_id: Option<String>,
+}
+
+impl Attachment {
+    pub fn new(filename: &str, content_type: &str, data: Vec<u8>) -> Self {
+        Self {
+            filename: filename.to_string(),
+            content_type: content_type.to_string(),
+            data,
+            content_id: None,
+        }
+    }
+
+    /// An image shown in the HTML body with `<img src="cid:{content_id}">`.
+    pub fn inline(content_id: &str, filename: &str, content_type: &str, data: Vec<u8>) -> Self {
+        Self {
+            content_id: Some(content_id.to_string()),
+            ..Self::new(filename, content_type, data)
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.content_id.is_some()
+    }
+}
+
+/// One node of a MIME tree.
+enum Part {
+    Leaf {
+        headers: Vec<String>,
+        body: String,
+    },
+    Multipart {
+        subtype: &'static str,
+        parts: Vec<Part>,
+    },
+}
+
+impl Part {
+    fn text(subtype: &str, text: &str) -> Self {
+        Part::Leaf {
+            headers: vec![
+                format!("Content-Type: text/{}; charset=utf-8", subtype),
+                "Content-Transfer-Encoding: quoted-printable".to_string(),
+            ],
+            body: encode_quoted_printable(text),
+        }
+    }
+
+    fn attachment(attachment: &Attachment) -> Self {
+        let disposition = if attachment.is_inline() {
+            "inline"
+        } else {
+            "attachment"
+        };
+        let mut headers = vec![
+            format!(
+                "Content-Type: {};\r\n {}",
+                strip_newlines(&attachment.content_type),
+                encode_param("name", &attachment.filename)
+            ),
+            "Content-Transfer-Encoding: base64".to_string(),
+            format!(
+                "Content-Disposition: {};\r\n {}",
+                disposition,
+                encode_param("filename", &attachment.filename)
+            ),
+        ];
+        if let Some(content_id) = &attachment.content_id {
+            headers.push(format!("Content-ID: <{}>", strip_newlines(content_id)));
+        }
+        Part::Leaf {
+            headers,
+            body: encode_base64_lines(&attachment.data),
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            Part::Leaf { headers, body } => {
+                for header in headers {
+                    out.push_str(header);
+                    out.push_str("\r\n");
+                }
+                out.push_str("\r\n");
+                out.push_str(body);
+            }
+            Part::Multipart { subtype, parts } => {
+                // "=_" can't appear in quoted-printable or base64 output.
+                let boundary = format!("=_{}", Uuid::new_v4().simple());
+                out.push_str(&format!(
+                    "Content-Type: multipart/{};\r\n boundary=\"{}\"\r\n\r\n",
+                    subtype, boundary
+                ));
+                for part in parts {
+                    out.push_str(&format!("--{}\r\n", boundary));
+                    part.write(out);
+                    out.push_str("\r\n");
+                }
+                out.push_str(&format!("--{}--", boundary));
+            }
+        }
+    }
+}
+
+/// Builds the RFC 5322 form of an `Email`: a multipart/alternative of plain text and HTML,
+/// with inline images related to the HTML and any other attachments mixed in alongside.
+pub struct MimeBuilder<'a> {
+    email: &'a Email,
+}
+
+impl<'a> MimeBuilder<'a> {
+    pub fn new(email: &'a Email) -> Self {
+        Self { email }
+    }
+
+    pub fn build(self) -> String {
+        let email = self.email;
+        let html = email.message.as_ref();
+        let text = email.plain_text();
+        let (inline, attached): (Vec<_>, Vec<_>) =
+            email.attachments.iter().partition(|a| a.is_inline());
+
+        let mut html_part = Part::text("html", html);
+        if !inline.is_empty() {
+            let mut parts = vec![html_part];
+            parts.extend(inline.into_iter().map(Part::attachment));
+            html_part = Part::Multipart {
+                subtype: "related",
+                parts,
+            };
+        }
+        let mut root = Part::Multipart {
+            subtype: "alternative",
+            parts: vec![Part::text("plain", &text), html_part],
+        };
+        if !attached.is_empty() {
+            let mut parts = vec![root];
+            parts.extend(attached.into_iter().map(Part::attachment));
+            root = Part::Multipart {
+                subtype: "mixed",
+                parts,
+            };
+        }
+
+        let mut message = format!(
+            "Date: {}\r\n\
+             From: {}\r\n\
+             To: {}\r\n",
+            chrono::Utc::now().to_rfc2822(),
+            email.from,
+            email.to,
+        );
+        // BCC recipients are only given to the transport, never written in the message.
+        let headers = &email.headers;
+        if !headers.cc.is_empty() {
+            let cc: Vec<_> = headers.cc.iter().map(|cc| cc.to_string()).collect();
+            message.push_str(&format!("Cc: {}\r\n", cc.join(",\r\n ")));
+        }
+        if let Some(reply_to) = &headers.reply_to {
+            message.push_str(&format!("Reply-To: {}\r\n", reply_to));
+        }
+        message.push_str(&format!(
+            "Subject: {}\r\n\
+             Message-ID: <{}>\r\n",
+            encode_header(&email.subject),
+            strip_newlines(&email.message_id),
+        ));
+        for (name, value) in headers.extra_headers() {
+            message.push_str(&format!("{}: {}\r\n", name, encode_header(&value)));
+        }
+        message.push_str("MIME-Version: 1.0\r\n");
+        root.write(&mut message);
+        message.push_str("\r\n");
+        message
+    }
+}
+
+fn strip_newlines(value: &str) -> String {
+    value.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+/// Encode a header value as RFC 2047 encoded words if it isn't plain ASCII.
+pub fn encode_header(value: &str) -> String {
+    let value = strip_newlines(value);
+    if value.is_ascii() {
+        return value;
+    }
+    let mut words = vec![];
+    let mut word = String::new();
+    for c in value.chars() {
+        if word.len() + c.len_utf8() > MAX_ENCODED_WORD_BYTES {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push(c);
+    }
+    words.push(word);
+    words
+        .iter()
+        .map(|word| format!("=?UTF-8?B?{}?=", base64::encode(word)))
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+/// A MIME parameter, using RFC 2231 encoding when the value isn't a plain quoted string.
+fn encode_param(name: &str, value: &str) -> String {
+    let value = strip_newlines(value);
+    if value.is_ascii() && !value.contains(['"', '\\']) {
+        format!("{}=\"{}\"", name, value)
+    } else {
+        format!("{}*=UTF-8''{}", name, urlencoding::encode(&value))
+    }
+}
+
+fn encode_base64_lines(data: &[u8]) -> String {
+    let encoded = base64::encode(data);
+    let mut out = String::with_capacity(encoded.len() + encoded.len() / MAX_LINE * 2);
+    for chunk in encoded.as_bytes().chunks(MAX_LINE) {
+        out.push_str(std::str::from_utf8(chunk).expect("base64 is ascii"));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Quoted-printable encoding with CRLF line endings and soft breaks for long lines.
+pub fn encode_quoted_printable(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for line in text.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let bytes = line.as_bytes();
+        let mut width = 0;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let last = i + 1 == bytes.len();
+            let literal = matches!(byte, b'!'..=b'<' | b'>'..=b'~')
+                || (matches!(byte, b' ' | b'\t') && !last);
+            let encoded = if literal {
+                (byte as char).to_string()
+            } else {
+                format!("={:02X}", byte)
+            };
+            // Leave room for the "=" of a soft break.
+            if width + encoded.len() > MAX_LINE - 1 {
+                out.push_str("=\r\n");
+                width = 0;
+            }
+            width += encoded.len();
+            out.push_str(&encoded);
+        }
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// A readable plain-text rendering of an HTML email, used when there is no text template.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut links: Vec<Option<String>> = vec![];
+    let mut skip_depth = 0;
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => break,
+            };
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            let closing = tag.starts_with('/');
+            let name = tag
+                .trim_start_matches('/')
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            match name.as_str() {
+                "style" | "script" | "head" | "title" => {
+                    if closing {
+                        skip_depth = usize::saturating_sub(skip_depth, 1);
+                    } else if !tag.ends_with('/') {
+                        skip_depth += 1;
+                    }
+                }
+                "br" => text.push('\n'),
+                "p" | "div" | "tr" | "table" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "ul"
+                | "ol" | "hr" => text.push_str("\n\n"),
+                "li" if !closing => text.push_str("\n- "),
+                "a" if closing => {
+                    if let Some(Some(href)) = links.pop() {
+                        if !text.trim_end().ends_with(&href) {
+                            text.push_str(&format!(" ({})", href));
+                        }
+                    }
+                }
+                "a" => links.push(attribute(tag, "href")),
+                _ => {}
+            }
+            continue;
+        }
+        let end = rest.find('<').unwrap_or(rest.len());
+        if skip_depth == 0 {
+            let mut last_space = text.ends_with([' ', '\n']);
+            for c in decode_entities(&rest[..end]).chars() {
+                if c.is_whitespace() {
+                    if !last_space {
+                        text.push(' ');
+                    }
+                    last_space = true;
+                } else {
+                    text.push(c);
+                    last_space = false;
+                }
+            }
+        }
+        rest = &rest[end..];
+    }
+
+    // Trim each line and allow at most one blank line in a row.
+    let mut out = String::new();
+    let mut blank = true;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            if !blank {
+                out.push('\n');
+            }
+            blank = true;
+        } else {
+            out.push_str(line);
+            out.push('\n');
+            blank = false;
+        }
+    }
+    out.trim_end().to_string()
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let start = lower.find(&format!("{}=", name))? + name.len() + 1;
+    let value = &tag[start..];
+    let value = match value.chars().next()? {
+        quote @ ('"' | '\'') => value[1..].split(quote).next()?,
+        _ => value.split(|c: char| c.is_whitespace()).next()?,
+    };
+    Some(decode_entities(value))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|d| d.parse().ok()))
+                    .and_then(char::from_u32),
+            }?;
+            Some((c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use email_address::EmailAddress;
+
+    use super::*;
+    use crate::email::headers::{EmailHeaders, ListUnsubscribe};
+    use crate::email::FilledTemplate;
+
+    #[test]
+    fn test_mime_builder() {
+        let html = "<html><head><style>p { color: red; }</style></head><body>\
+                    <p>Hi&nbsp;there,</p><p>Please <a href=\"https://example.com/v?a=1&amp;b=2\">\
+                    verify</a> your email.</p><img src=\"cid:logo\"></body></html>";
+        let email = Email::new(
+            &EmailAddress::from_str("test@example.com").unwrap(),
+            &EmailAddress::from_str("noreply@example.com").unwrap(),
+            "Vérifiez votre adresse",
+            FilledTemplate(html.to_string()),
+        )
+        .attach(Attachment::inline(
+            "logo",
+            "logo.png",
+            "image/png",
+            vec![0x89, b'P', b'N', b'G'],
+        ))
+        .attach(Attachment::new(
+            "reçu.pdf",
+            "application/pdf",
+            b"%PDF".to_vec(),
+        ))
+        .with_headers(
+            EmailHeaders::default()
+                .reply_to(EmailAddress::from_str("support@example.com").unwrap())
+                .cc(EmailAddress::from_str("cc@example.com").unwrap())
+                .bcc(EmailAddress::from_str("hidden@example.com").unwrap())
+                .list_unsubscribe(ListUnsubscribe::one_click(
+                    "https://example.com/unsubscribe",
+                ))
+                .header("X-Entity-Ref-ID", "42")
+                .header("Subject", "Overridden"),
+        );
+
+        assert_eq!(
+            html_to_text(html),
+            "Hi there,\n\nPlease verify (https://example.com/v?a=1&b=2) your email."
+        );
+
+        let message = MimeBuilder::new(&email).build();
+        assert!(message.contains("Subject: =?UTF-8?B?VsOpcmlmaWV6IHZvdHJlIGFkcmVzc2U=?=\r\n"));
+        assert!(message.contains("Cc: cc@example.com\r\n"));
+        assert!(message.contains("Reply-To: support@example.com\r\n"));
+        assert!(message.contains(&format!("Message-ID: <{}>\r\n", email.message_id)));
+        assert!(email.message_id.ends_with("@example.com"));
+        assert!(message.contains("List-Unsubscribe: <https://example.com/unsubscribe>\r\n"));
+        assert!(message.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"));
+        assert!(message.contains("X-Entity-Ref-ID: 42\r\n"));
+        assert!(!message.contains("hidden@example.com"));
+        assert!(!message.contains("Overridden"));
+        assert_eq!(email.recipients().count(), 3);
+        assert!(message.contains("Content-Type: multipart/mixed;"));
+        assert!(message.contains("Content-Type: multipart/alternative;"));
+        assert!(message.contains("Content-Type: multipart/related;"));
+        assert!(message.contains("Content-ID: <logo>\r\n"));
+        assert!(message.contains("filename*=UTF-8''re%C3%A7u.pdf"));
+        // Each part is nested in the expected order.
+        let mixed = message.find("multipart/mixed").unwrap();
+        let plain = message.find("text/plain").unwrap();
+        let related = message.find("multipart/related").unwrap();
+        let png = message.find("image/png").unwrap();
+        let pdf = message.find("application/pdf").unwrap();
+        assert!(mixed < plain && plain < related && related < png && png < pdf);
+        // Headers are folded within 78 characters and bodies within 76.
+        assert!(message.lines().all(|line| line.len() <= 78));
+
+        let long = "é".repeat(40) + " end ";
+        let encoded = encode_quoted_printable(&long);
+        assert!(encoded.lines().all(|line| line.len() <= MAX_LINE));
+        assert!(encoded.ends_with("end=20\r\n"));
+    }
+}
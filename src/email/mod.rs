@@ -0,0 +1,1289 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+use email_address::EmailAddress;
+use handlebars::Handlebars;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+use self::delayed::{next_cancel, sleep_until_due, CancelScheduledEmail, DelayedEmails};
+use self::handle::{SchedulerHandle, SchedulerMetrics};
+use self::headers::EmailHeaders;
+use self::locale::{Locale, SubjectCatalog};
+use self::mime::Attachment;
+use self::preview::RecentEmails;
+use self::reload::Templates;
+use self::transport::DeliveryReport;
+use self::validate::validate_templates;
+use crate::{
+    rate_limit::{
+        rate_limited_channel, KeyedRateLimit, KeyedRateLimiter, RateLimit, RateLimitProfile,
+        RateLimitedReceiver, RateLimitedSender,
+    },
+    router::ChannelRouter,
+    tables::{
+        outbox::OUTBOX_SENDING, DbPool, OutboxEmail, Suppression, TokenPurpose,
+        UnverifiedEmailTable, UserId, UserTable,
+    },
+};
+
+pub mod capture;
+pub mod delayed;
+pub mod delivery;
+pub mod digest;
+pub mod dkim;
+pub mod file;
+pub mod handle;
+pub mod headers;
+pub mod helpers;
+pub mod locale;
+pub mod mime;
+pub mod preview;
+pub mod reload;
+pub mod smtp;
+pub mod suppression;
+pub mod transport;
+pub mod validate;
+
+/// How a verification is delivered: a link to follow or a numeric code to type in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerificationMode {
+    #[default]
+    Link,
+    Code,
+}
+
+pub async fn send_verification_email<E, B, T, U>(
+    conn: &mut AsyncPgConnection,
+    base_url: &str,
+    to_address: EmailAddress,
+    builder: B,
+    email_tx: broadcast::Sender<ScheduledEmail<T>>,
+) -> anyhow::Result<()>
+where
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U>,
+    T: EmailTemplate,
+    U: UserTable,
+{
+    let email_link = E::create(conn, &to_address, base_url).await?;
+    let template = builder.unique_link(&email_link).build()?;
+    let email = ScheduledEmail::new(to_address, template).transactional();
+    if email_tx.send(email).is_err() {
+        tracing::warn!("No email scheduler is running, verification email dropped");
+    }
+    Ok(())
+}
+
+/// Send a single-use sign-in link to `to_address`. The link leads to the magic link callback
+/// route, which returns the user to `origin` once they are signed in.
+pub async fn send_login_email<E, B, T, U>(
+    conn: &mut AsyncPgConnection,
+    base_url: &str,
+    to_address: EmailAddress,
+    origin: &str,
+    builder: B,
+    email_tx: broadcast::Sender<ScheduledEmail<T>>,
+) -> anyhow::Result<()>
+where
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U>,
+    T: EmailTemplate,
+    U: UserTable,
+{
+    let token = E::create_token(conn, &to_address, TokenPurpose::Login).await?;
+    let link = format!(
+        "{}auth/magic/callback?token={}&origin={}",
+        base_url,
+        token,
+        urlencoding::encode(origin)
+    );
+    let template = builder.unique_link(&link).build()?;
+    let email = ScheduledEmail::new(to_address, template).transactional();
+    if email_tx.send(email).is_err() {
+        tracing::warn!("No email scheduler is running, login email dropped");
+    }
+    Ok(())
+}
+
+/// Like `send_verification_email`, but sends a numeric code of `digits` digits for clients which
+/// can't follow links. The code is checked with `UnverifiedEmailTable::verify_code`.
+pub async fn send_verification_code<E, B, T, U>(
+    conn: &mut AsyncPgConnection,
+    to_address: EmailAddress,
+    digits: u32,
+    builder: B,
+    email_tx: broadcast::Sender<ScheduledEmail<T>>,
+) -> anyhow::Result<()>
+where
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U>,
+    T: EmailTemplate,
+    U: UserTable,
+{
+    let code = E::create_code(conn, &to_address, digits).await?;
+    let template = builder.verification_code(&code).build()?;
+    let email = ScheduledEmail::new(to_address, template).transactional();
+    if email_tx.send(email).is_err() {
+        tracing::warn!("No email scheduler is running, verification email dropped");
+    }
+    Ok(())
+}
+
+/// Like `send_verification_email`, but the email is written to the durable outbox instead of
+/// being broadcast. Run this inside the caller's transaction so the pending verification and its
+/// email are committed together; `dispatch_outbox` picks the email up afterwards.
+pub async fn queue_verification_email<E, B, T, U>(
+    conn: &mut AsyncPgConnection,
+    base_url: &str,
+    to_address: EmailAddress,
+    builder: B,
+) -> anyhow::Result<OutboxEmail>
+where
+    E: UnverifiedEmailTable,
+    B: EmailTemplateBuilder<T, U>,
+    T: EmailTemplate + Serialize + 'static,
+    U: UserTable,
+{
+    let email_link = E::create(conn, &to_address, base_url).await?;
+    let template = builder.unique_link(&email_link).build()?;
+    let email = ScheduledEmail::new(to_address, template).transactional();
+    OutboxEmail::enqueue(conn, &email).await
+}
+
+/// Limits for resending verification emails.
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleProfile {
+    /// Limit applied to each user.
+    pub per_user: KeyedRateLimit,
+    /// Limit applied to each email address, across every user claiming it.
+    pub per_address: KeyedRateLimit,
+    /// The most pending verifications kept per address. Issuing another deletes the oldest.
+    pub max_outstanding: i64,
+    /// Limit on verification code guesses for each email address.
+    pub code_attempts: KeyedRateLimit,
+}
+
+impl Default for ThrottleProfile {
+    fn default() -> Self {
+        let limit = KeyedRateLimit {
+            cooldown: Duration::from_secs(60),
+            max_rate: RateLimit {
+                rate_per_window: 5,
+                window: Duration::from_secs(60 * 60),
+            },
+        };
+        Self {
+            per_user: limit,
+            per_address: limit,
+            max_outstanding: 3,
+            code_attempts: KeyedRateLimit {
+                cooldown: Duration::from_secs(1),
+                max_rate: RateLimit {
+                    rate_per_window: 10,
+                    window: Duration::from_secs(15 * 60),
+                },
+            },
+        }
+    }
+}
+
+/// Tracks verification email resends so a single client can't flood a mailbox, and code guesses
+/// so a code can't be brute forced across several sessions.
+///
+/// Counts are kept in memory, so each replica enforces the limits separately.
+pub struct VerificationThrottle {
+    per_user: KeyedRateLimiter<UserId>,
+    per_address: KeyedRateLimiter<String>,
+    code_attempts: KeyedRateLimiter<String>,
+    max_outstanding: i64,
+}
+
+impl VerificationThrottle {
+    pub fn new(profile: ThrottleProfile) -> Self {
+        Self {
+            per_user: KeyedRateLimiter::new(profile.per_user),
+            per_address: KeyedRateLimiter::new(profile.per_address),
+            code_attempts: KeyedRateLimiter::new(profile.code_attempts),
+            max_outstanding: profile.max_outstanding,
+        }
+    }
+
+    pub fn max_outstanding(&self) -> i64 {
+        self.max_outstanding
+    }
+
+    /// Record a send for `user_id` to `email` if both limits allow it, otherwise return how long
+    /// until they do. Nothing is recorded when the send is rejected.
+    pub fn check(&self, user_id: UserId, email: &EmailAddress) -> Result<(), Duration> {
+        let address = email.as_str().to_lowercase();
+        self.per_user
+            .check_with(user_id, &self.per_address, address)
+    }
+
+    /// Record a send to `email` if the per-address limit allows it, otherwise return how long
+    /// until it does. For requests which mustn't reveal whether the address has an account, so
+    /// they can't be limited per user.
+    pub fn check_address(&self, email: &EmailAddress) -> Result<(), Duration> {
+        self.per_address.check(email.as_str().to_lowercase())
+    }
+
+    /// Record a verification code guess for `email` if the limit allows it, otherwise return
+    /// how long until it does.
+    pub fn check_code(&self, email: &EmailAddress) -> Result<(), Duration> {
+        self.code_attempts.check(email.as_str().to_lowercase())
+    }
+}
+
+impl Default for VerificationThrottle {
+    fn default() -> Self {
+        Self::new(ThrottleProfile::default())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxProfile {
+    /// How often the outbox is polled for pending emails.
+    pub interval: Duration,
+    /// The most emails claimed in one poll.
+    pub batch_size: i64,
+    /// Attempts before an email which cannot be scheduled or delivered is marked failed.
+    pub max_attempts: i32,
+    /// How long a dispatched email may go without a delivery report before it is sent again.
+    pub ack_timeout: Duration,
+}
+
+impl Default for OutboxProfile {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            batch_size: 100,
+            max_attempts: 5,
+            ack_timeout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// Moves emails from the durable outbox into the `schedule_emails` pipeline.
+///
+/// Rows are claimed with `FOR UPDATE SKIP LOCKED` so several replicas can run a dispatcher
+/// against the same outbox. An email is marked sending once it has been handed to the scheduler,
+/// then sent or failed from its `DeliveryReport` on `reports`, so give the scheduler's
+/// `Delivery` the sender of that channel. Emails whose report doesn't arrive within
+/// `OutboxProfile::ack_timeout`, such as those lost to a restart, are sent again. While no
+/// scheduler is running, emails are left pending.
+pub fn dispatch_outbox<T>(
+    pool: Arc<DbPool>,
+    schedule_tx: broadcast::Sender<ScheduledEmail<T>>,
+    mut reports: broadcast::Receiver<DeliveryReport>,
+    profile: OutboxProfile,
+) -> JoinHandle<()>
+where
+    T: EmailTemplate + DeserializeOwned + Sync + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(profile.interval);
+        let mut reporting = true;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => dispatch_batch(&pool, &schedule_tx, profile).await,
+                report = reports.recv(), if reporting => match report {
+                    Ok(report) => acknowledge_report(&pool, report, profile).await,
+                    Err(RecvError::Lagged(skipped)) => tracing::warn!(
+                        "Outbox dispatcher missed {} delivery reports, their emails will be sent again",
+                        skipped
+                    ),
+                    Err(RecvError::Closed) => {
+                        tracing::warn!("Delivery reports closed, outbox emails will be sent again");
+                        reporting = false;
+                    }
+                },
+            }
+        }
+    })
+}
+
+async fn dispatch_batch<T>(
+    pool: &DbPool,
+    schedule_tx: &broadcast::Sender<ScheduledEmail<T>>,
+    profile: OutboxProfile,
+) where
+    T: EmailTemplate + DeserializeOwned + Sync + 'static,
+{
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::error!("Outbox dispatcher could not get a connection: {}", err);
+            return;
+        }
+    };
+    let result = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                let claimed =
+                    OutboxEmail::claim(conn, profile.batch_size, profile.ack_timeout).await?;
+                let mut sending = vec![];
+                for row in claimed {
+                    if row.status == OUTBOX_SENDING && row.attempts >= profile.max_attempts {
+                        row.mark_failed(conn, "No delivery report", profile.max_attempts)
+                            .await?;
+                        continue;
+                    }
+                    let email = match outbox_email::<T>(&row) {
+                        Ok(email) => email,
+                        Err(err) => {
+                            tracing::warn!("Outbox email {} not scheduled: {}", row.id, err);
+                            row.mark_failed(conn, &err.to_string(), profile.max_attempts)
+                                .await?;
+                            continue;
+                        }
+                    };
+                    if schedule_tx.send(email).is_err() {
+                        tracing::warn!("No email scheduler is running, outbox emails left pending");
+                        break;
+                    }
+                    sending.push(row.id);
+                }
+                OutboxEmail::mark_sending(conn, &sending).await
+            }
+            .scope_boxed()
+        })
+        .await;
+    match result {
+        Ok(0) => {}
+        Ok(sent) => tracing::debug!("Outbox dispatched {} emails", sent),
+        Err(err) => tracing::error!("Outbox dispatch failed: {}", err),
+    }
+}
+
+async fn acknowledge_report(pool: &DbPool, report: DeliveryReport, profile: OutboxProfile) {
+    if report.outbox_ids.is_empty() {
+        return;
+    }
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::error!("Outbox dispatcher could not get a connection: {}", err);
+            return;
+        }
+    };
+    for id in report.outbox_ids {
+        let acknowledged = OutboxEmail::acknowledge(
+            &mut conn,
+            id,
+            report.error.as_deref(),
+            report.permanent,
+            profile.max_attempts,
+        )
+        .await;
+        if let Err(err) = acknowledged {
+            tracing::error!("Could not acknowledge outbox email {}: {}", id, err);
+        }
+    }
+}
+
+fn outbox_email<T>(row: &OutboxEmail) -> anyhow::Result<ScheduledEmail<T>>
+where
+    T: EmailTemplate + DeserializeOwned + 'static,
+{
+    let mut email = ScheduledEmail::new(
+        EmailAddress::from_str(&row.recipient)?,
+        serde_json::from_value(row.template.clone())?,
+    )
+    .with_headers(serde_json::from_value(row.headers.clone())?);
+    email.transactional = row.transactional;
+    email.send_at = row.send_at.map(|send_at| send_at.and_utc());
+    email.cancel_key = row.cancel_key.clone();
+    email.digest = row.digest;
+    email.outbox_ids = vec![row.id];
+    Ok(email)
+}
+
+/// The templates subdirectory of shared partials, registered by file name.
+pub const PARTIALS_DIR: &str = "partials";
+/// The templates subdirectory of layouts, registered as partials like `PARTIALS_DIR`.
+pub const LAYOUTS_DIR: &str = "layouts";
+
+/// Intended to be used with an HTML-based template.
+/// I use Maizzle for this.
+pub fn setup_handlebars(templates_dir: &PathBuf) -> Result<Handlebars<'static>> {
+    let mut handlebars = Handlebars::new();
+    register_templates(&mut handlebars, templates_dir, templates_dir)?;
+    for (name, path) in partial_files(templates_dir)? {
+        handlebars.register_partial(&name, std::fs::read_to_string(&path)?)?;
+    }
+    helpers::register_helpers(&mut handlebars);
+
+    Ok(handlebars)
+}
+
+/// Each file in the `PARTIALS_DIR` and `LAYOUTS_DIR` of `templates_dir` with the partial name
+/// it is registered under, so `partials/footer.html` is `{{> footer}}`, `partials/footer.txt`
+/// is `{{> footer.txt}}` and `layouts/base.html` wraps a template with `{{#> base}}...{{/base}}`,
+/// rendering it at `{{> @partial-block}}`.
+pub(crate) fn partial_files(templates_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut partials = vec![];
+    for dir in [PARTIALS_DIR, LAYOUTS_DIR] {
+        let dir = templates_dir.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let name = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("html") => stem.to_string(),
+                Some("txt") => format!("{}.txt", stem),
+                _ => continue,
+            };
+            partials.push((name, path));
+        }
+    }
+    Ok(partials)
+}
+
+/// Register each `.html` template by its path without the extension, and each `.txt` template
+/// as `<name>.txt` next to the `<name>` HTML template it is the plain-text version of. Hidden
+/// files and the `PARTIALS_DIR` and `LAYOUTS_DIR` subdirectories are skipped.
+fn register_templates(handlebars: &mut Handlebars, root: &PathBuf, dir: &PathBuf) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+        let name = path
+            .strip_prefix(root)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        if path.is_dir() {
+            if name != PARTIALS_DIR && name != LAYOUTS_DIR {
+                register_templates(handlebars, root, &path)?;
+            }
+        } else if let Some(name) = name.strip_suffix(".html") {
+            handlebars.register_template_file(name, &path)?;
+        } else if name.ends_with(".txt") {
+            handlebars.register_template_file(&name, &path)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct FilledTemplate(pub String);
+
+impl AsRef<str> for FilledTemplate {
+    fn as_ref(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl FilledTemplate {
+    pub fn new<S: Serialize>(
+        handlebars: &Handlebars,
+        template: &str,
+        template_data: &S,
+    ) -> anyhow::Result<FilledTemplate> {
+        let rendered = handlebars.render(template, template_data)?;
+        Ok(FilledTemplate(rendered))
+    }
+
+    /// Render the companion `<template>.txt` plain-text template, if there is one.
+    pub fn new_text<S: Serialize>(
+        handlebars: &Handlebars,
+        template: &str,
+        template_data: &S,
+    ) -> anyhow::Result<Option<FilledTemplate>> {
+        let text_template = format!("{}.txt", template);
+        if !handlebars.has_template(&text_template) {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(handlebars, &text_template, template_data)?))
+    }
+}
+
+pub trait EmailTemplate: Clone + std::fmt::Debug + Send {
+    /// The subject of the email.
+    fn subject(&self) -> String;
+
+    /// Fill the template with the handlebars instance.
+    fn fill(self, handlebars: &Handlebars) -> Result<FilledTemplate>;
+
+    /// The plain-text version of the email. Without one, the text is derived from the HTML.
+    fn fill_text(&self, _handlebars: &Handlebars) -> Result<Option<FilledTemplate>> {
+        Ok(None)
+    }
+
+    /// Examples of this template which `validate::validate_templates` renders at startup.
+    /// Include one for each handlebars template the emails can use.
+    fn samples() -> Vec<Self> {
+        vec![]
+    }
+
+    /// Files to send with the email, including images referenced from the HTML by `cid:`.
+    fn attachments(&self) -> Vec<Attachment> {
+        vec![]
+    }
+
+    /// Headers every email from this template carries, such as List-Unsubscribe for
+    /// newsletters. Headers on the `ScheduledEmail` are added to these.
+    fn headers(&self) -> EmailHeaders {
+        EmailHeaders::default()
+    }
+}
+
+pub trait EmailTemplateBuilder<Template, User>: Sized
+where
+    Template: EmailTemplate,
+    User: UserTable,
+{
+    fn new(
+        conn: &mut AsyncPgConnection,
+        user: &User,
+    ) -> impl std::future::Future<Output = anyhow::Result<Self>> + Send;
+    fn unique_link(self, link: &str) -> Self;
+    /// Set the numeric code for a verification email. Templates which show the code differently
+    /// from a link should override this.
+    fn verification_code(self, code: &str) -> Self {
+        self.unique_link(code)
+    }
+    fn subject(self, subject: &str) -> Self;
+    /// Write the email in `locale`. Builders with translated templates should keep it and
+    /// render `locale::resolve_template`.
+    fn locale(self, _locale: &Locale) -> Self {
+        self
+    }
+    /// Set the subject of the `template` email in `locale`. Looks in
+    /// `SubjectCatalog::builtin` unless overridden to use an application's own catalog.
+    fn localized_subject(self, template: &str, locale: &Locale) -> Self {
+        match SubjectCatalog::builtin().get(template, locale) {
+            Some(subject) => self.subject(subject),
+            None => self,
+        }
+    }
+    fn build(self) -> anyhow::Result<Template>;
+}
+
+#[derive(Clone, Debug)]
+pub struct ScheduledEmail<T: EmailTemplate + 'static> {
+    pub to: EmailAddress,
+    pub template: T,
+    pub headers: EmailHeaders,
+    /// Sent even to suppressed addresses, for email the recipient asked for such as sign in
+    /// links.
+    pub transactional: bool,
+    /// Held until this time instead of being sent right away.
+    pub send_at: Option<DateTime<Utc>>,
+    /// Cancels the email while it is held, see `delayed::cancel_scheduled_email`.
+    pub cancel_key: Option<String>,
+    /// May be combined with other notifications to the same recipient by `digest::digest_emails`.
+    pub digest: bool,
+    /// The outbox rows this email was dispatched from, acknowledged by `dispatch_outbox` once
+    /// its delivery is reported.
+    pub outbox_ids: Vec<Uuid>,
+}
+
+impl<T: EmailTemplate + 'static> ScheduledEmail<T> {
+    pub fn new(to: EmailAddress, template: T) -> Self {
+        Self {
+            to,
+            template,
+            headers: EmailHeaders::default(),
+            transactional: false,
+            send_at: None,
+            cancel_key: None,
+            digest: false,
+            outbox_ids: vec![],
+        }
+    }
+
+    pub fn transactional(mut self) -> Self {
+        self.transactional = true;
+        self
+    }
+
+    pub fn with_headers(mut self, headers: EmailHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Send at `send_at` rather than right away. Enqueue the email to the outbox for it to
+    /// survive restarts until then.
+    pub fn send_at(mut self, send_at: DateTime<Utc>) -> Self {
+        self.send_at = Some(send_at);
+        self
+    }
+
+    pub fn cancel_key(mut self, cancel_key: &str) -> Self {
+        self.cancel_key = Some(cancel_key.to_string());
+        self
+    }
+
+    pub fn digestable(mut self) -> Self {
+        self.digest = true;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: EmailAddress,
+    pub from: EmailAddress,
+    pub subject: String,
+    pub message: FilledTemplate,
+    /// The plain-text alternative to `message`.
+    pub text: Option<String>,
+    pub attachments: Vec<Attachment>,
+    pub headers: EmailHeaders,
+    /// The Message-ID, without angle brackets.
+    pub message_id: String,
+    /// See `ScheduledEmail::outbox_ids`.
+    pub outbox_ids: Vec<Uuid>,
+}
+
+impl Email {
+    pub fn new(
+        to: &EmailAddress,
+        from: &EmailAddress,
+        subject: &str,
+        message: FilledTemplate,
+    ) -> Self {
+        Self {
+            to: to.clone(),
+            from: from.clone(),
+            subject: subject.to_string(),
+            message,
+            text: None,
+            attachments: vec![],
+            headers: EmailHeaders::default(),
+            message_id: format!("{}@{}", Uuid::new_v4(), from.domain()),
+            outbox_ids: vec![],
+        }
+    }
+
+    pub fn with_headers(mut self, headers: EmailHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Everyone the email is delivered to: the recipient, then CC and BCC.
+    pub fn recipients(&self) -> impl Iterator<Item = &EmailAddress> {
+        std::iter::once(&self.to)
+            .chain(&self.headers.cc)
+            .chain(&self.headers.bcc)
+    }
+
+    pub fn with_text(mut self, text: FilledTemplate) -> Self {
+        self.text = Some(text.0);
+        self
+    }
+
+    pub fn attach(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    /// The plain-text alternative, derived from the HTML if there is no text template.
+    pub fn plain_text(&self) -> String {
+        match &self.text {
+            Some(text) => text.clone(),
+            None => mime::html_to_text(self.message.as_ref()),
+        }
+    }
+}
+
+/// Render the emails from `schedule_rx` and hand them to `send_email`, with the defaults of
+/// `EmailScheduler`. The suppression list is not checked, so bounced and complaining addresses
+/// are still emailed; build an `EmailScheduler` with `check_suppressions` for that.
+pub fn schedule_emails<T, F, Fut>(
+    from: EmailAddress,
+    templates_dir: PathBuf,
+    schedule_rx: broadcast::Receiver<ScheduledEmail<T>>,
+    send_email: F,
+    profile: RateLimitProfile,
+) -> SchedulerHandle
+where
+    T: EmailTemplate,
+    F: FnOnce(RateLimitedReceiver<Email>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    EmailScheduler::new(from, templates_dir, schedule_rx, profile).spawn(send_email)
+}
+
+/// Renders `ScheduledEmail`s and hands them to `send_email` at a limited rate. `schedule_emails`
+/// is the same scheduler with the default options.
+pub struct EmailScheduler<T: EmailTemplate + 'static> {
+    from: EmailAddress,
+    templates_dir: PathBuf,
+    schedule_rx: broadcast::Receiver<ScheduledEmail<T>>,
+    profile: RateLimitProfile,
+    suppressions: Option<Arc<DbPool>>,
+    watch: Option<Duration>,
+    reload_router: Option<ChannelRouter>,
+    templates: Option<Arc<Templates>>,
+    recent: Option<Arc<RecentEmails>>,
+    cancel_rx: Option<broadcast::Receiver<CancelScheduledEmail>>,
+}
+
+impl<T: EmailTemplate + 'static> EmailScheduler<T> {
+    pub fn new(
+        from: EmailAddress,
+        templates_dir: PathBuf,
+        schedule_rx: broadcast::Receiver<ScheduledEmail<T>>,
+        profile: RateLimitProfile,
+    ) -> Self {
+        Self {
+            from,
+            templates_dir,
+            schedule_rx,
+            profile,
+            suppressions: None,
+            watch: None,
+            reload_router: None,
+            templates: None,
+            recent: None,
+            cancel_rx: None,
+        }
+    }
+
+    /// Drop emails to addresses on the suppression list unless they are marked transactional.
+    pub fn check_suppressions(mut self, pool: Arc<DbPool>) -> Self {
+        self.suppressions = Some(pool);
+        self
+    }
+
+    /// Reload the templates whenever a file in `templates_dir` changes, checking every
+    /// `period`. Meant for development.
+    pub fn watch_templates(mut self, period: Duration) -> Self {
+        self.watch = Some(period);
+        self
+    }
+
+    /// Reload the templates whenever `ReloadTemplates` is announced on `router`.
+    pub fn reload_templates_on(mut self, router: &ChannelRouter) -> Self {
+        self.reload_router = Some(router.clone());
+        self
+    }
+
+    /// Render with `templates`, shared with e.g. an `EmailPreview`, instead of loading
+    /// `templates_dir` when spawned.
+    pub fn templates(mut self, templates: Arc<Templates>) -> Self {
+        self.templates = Some(templates);
+        self
+    }
+
+    /// Keep the most recently rendered emails in `recent` for previewing.
+    pub fn capture(mut self, recent: Arc<RecentEmails>) -> Self {
+        self.recent = Some(recent);
+        self
+    }
+
+    /// Cancel held delayed emails when `CancelScheduledEmail` is announced on `router`.
+    pub fn cancel_on(mut self, router: &ChannelRouter) -> Self {
+        self.cancel_rx = Some(router.subscribe());
+        self
+    }
+
+    /// Render every `EmailTemplate::samples` with the scheduler's templates, failing with
+    /// a `validate::TemplateValidationError` listing each broken one.
+    pub fn validate(&self) -> Result<()> {
+        let handlebars = match &self.templates {
+            Some(templates) => templates.get(),
+            None => Arc::new(setup_handlebars(&self.templates_dir)?),
+        };
+        validate_templates::<T>(&handlebars)?;
+        Ok(())
+    }
+
+    /// Start rendering and sending. The returned handle shuts the scheduler down gracefully.
+    pub fn spawn<F, Fut>(self, send_email: F) -> SchedulerHandle
+    where
+        F: FnOnce(RateLimitedReceiver<Email>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let Self {
+            from,
+            templates_dir,
+            mut schedule_rx,
+            profile,
+            suppressions,
+            watch,
+            reload_router,
+            templates,
+            recent,
+            mut cancel_rx,
+        } = self;
+        let (tx, rx) = rate_limited_channel(profile);
+        let shutdown = Arc::new(Notify::new());
+        let metrics = Arc::new(SchedulerMetrics::default());
+
+        let scheduler = tokio::spawn({
+            let shutdown = shutdown.clone();
+            let metrics = metrics.clone();
+            async move {
+                let templates = templates.unwrap_or_else(|| {
+                    Templates::load(templates_dir).expect("Failed to setup handlebars")
+                });
+                if let Some(period) = watch {
+                    templates.watch(period);
+                }
+                if let Some(router) = &reload_router {
+                    templates.reload_on(router);
+                }
+                let stage = RenderStage {
+                    from,
+                    templates,
+                    suppressions,
+                    recent,
+                    metrics,
+                    tx,
+                };
+
+                let mut delayed = DelayedEmails::new();
+                'schedule: loop {
+                    let next_due = delayed.next_due();
+                    tokio::select! {
+                        received = schedule_rx.recv() => match received {
+                            Ok(email) => {
+                                if !stage.receive(email, &mut delayed).await {
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Lagged(skipped)) => stage.skipped(skipped),
+                            Err(RecvError::Closed) => break,
+                        },
+                        _ = shutdown.notified() => {
+                            // Everything already in the channel is still rendered and sent.
+                            loop {
+                                match schedule_rx.try_recv() {
+                                    Ok(email) => {
+                                        if !stage.receive(email, &mut delayed).await {
+                                            break 'schedule;
+                                        }
+                                    }
+                                    Err(TryRecvError::Lagged(skipped)) => stage.skipped(skipped),
+                                    Err(_) => break 'schedule,
+                                }
+                            }
+                        }
+                        cancel_key = next_cancel(&mut cancel_rx) => delayed.cancel(&cancel_key),
+                        _ = sleep_until_due(next_due) => {
+                            for email in delayed.take_due() {
+                                if !stage.schedule(email).await {
+                                    break 'schedule;
+                                }
+                            }
+                        }
+                    }
+                }
+                if delayed.len() > 0 {
+                    tracing::warn!("Dropping {} delayed emails", delayed.len());
+                }
+                tracing::info!("Email scheduler shutting down");
+            }
+        });
+
+        SchedulerHandle {
+            shutdown,
+            metrics,
+            scheduler,
+            sender: tokio::spawn(send_email(rx)),
+        }
+    }
+}
+
+/// Everything an email goes through between being scheduled and being handed to `send_email`.
+struct RenderStage {
+    from: EmailAddress,
+    templates: Arc<Templates>,
+    suppressions: Option<Arc<DbPool>>,
+    recent: Option<Arc<RecentEmails>>,
+    metrics: Arc<SchedulerMetrics>,
+    tx: RateLimitedSender<Email>,
+}
+
+impl RenderStage {
+    /// Schedule `email` now, or hold it if it is delayed. Returns false once `send_email` has
+    /// stopped receiving.
+    async fn receive<T: EmailTemplate>(
+        &self,
+        email: ScheduledEmail<T>,
+        delayed: &mut DelayedEmails<T>,
+    ) -> bool {
+        self.metrics.add_received();
+        match delayed.hold(email) {
+            Some(email) => self.schedule(email).await,
+            None => true,
+        }
+    }
+
+    fn skipped(&self, skipped: u64) {
+        self.metrics.add_skipped(skipped);
+        tracing::warn!("Email scheduler fell behind and skipped {} emails", skipped);
+    }
+
+    /// Returns false once `send_email` has stopped receiving.
+    async fn schedule<T: EmailTemplate>(&self, mut scheduled_email: ScheduledEmail<T>) -> bool {
+        if let Some(pool) = &self.suppressions {
+            if !scheduled_email.transactional
+                && !remove_suppressed(pool, &mut scheduled_email).await
+            {
+                return true;
+            }
+        }
+        let to = scheduled_email.to.clone();
+        let email = match render_email(&self.templates.get(), &self.from, scheduled_email) {
+            Ok(email) => email,
+            Err(err) => {
+                tracing::error!("Could not render email to {}: {:#}", to, err);
+                return true;
+            }
+        };
+        if let Some(recent) = &self.recent {
+            recent.record(&email);
+        }
+        let sent = self.tx.send(email).await.is_ok();
+        if sent {
+            self.metrics.add_rendered();
+        }
+        sent
+    }
+}
+
+fn render_email<T: EmailTemplate>(
+    handlebars: &Handlebars,
+    from: &EmailAddress,
+    scheduled_email: ScheduledEmail<T>,
+) -> Result<Email> {
+    let ScheduledEmail {
+        to,
+        template,
+        headers,
+        outbox_ids,
+        ..
+    } = scheduled_email;
+    let subject = template.subject();
+    let text = template.fill_text(handlebars)?;
+    let attachments = template.attachments();
+    let headers = template.headers().merge(headers);
+    let filled_template = template.fill(handlebars)?;
+    let mut email = Email::new(&to, from, &subject, filled_template);
+    email.text = text.map(|text| text.0);
+    email.attachments = attachments;
+    email.headers = headers;
+    email.outbox_ids = outbox_ids;
+    Ok(email)
+}
+
+async fn is_suppressed(conn: &mut AsyncPgConnection, email: &EmailAddress) -> bool {
+    // Deliver when the list can't be checked rather than lose the email.
+    Suppression::is_suppressed(conn, email)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::error!("Suppression check for {} failed: {}", email, err);
+            false
+        })
+}
+
+/// Drop suppressed CC and BCC recipients. Returns false if the recipient itself is suppressed.
+async fn remove_suppressed<T: EmailTemplate>(pool: &DbPool, email: &mut ScheduledEmail<T>) -> bool {
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::error!("Suppression check could not get a connection: {}", err);
+            return true;
+        }
+    };
+    if is_suppressed(&mut conn, &email.to).await {
+        tracing::info!("Not emailing suppressed address {}", email.to);
+        return false;
+    }
+    let headers = &mut email.headers;
+    for copies in [&mut headers.cc, &mut headers.bcc] {
+        let mut kept = vec![];
+        for address in copies.drain(..) {
+            if !is_suppressed(&mut conn, &address).await {
+                kept.push(address);
+            }
+        }
+        *copies = kept;
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use function_name::named;
+    use tokio::time::timeout;
+
+    use super::capture::CaptureTransport;
+    use super::transport::run_transport;
+    use super::*;
+    use crate::rate_limit::RateLimit;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+    use crate::tables::{establish_connection_pool, SuppressionReason};
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct TestTemplate;
+
+    impl EmailTemplate for TestTemplate {
+        fn subject(&self) -> String {
+            "Test".to_string()
+        }
+
+        fn fill(self, _handlebars: &Handlebars) -> Result<FilledTemplate> {
+            Ok(FilledTemplate("<p>Test</p>".to_string()))
+        }
+    }
+
+    fn address(email: &str) -> EmailAddress {
+        EmailAddress::from_str(email).expect("valid email")
+    }
+
+    /// An email from `noreply@example.com`, for the transport tests.
+    pub(crate) fn email(to: &str, subject: &str, html: &str) -> Email {
+        Email::new(
+            &address(to),
+            &address("noreply@example.com"),
+            subject,
+            FilledTemplate(html.to_string()),
+        )
+    }
+
+    async fn receive(rx: &mut tokio::sync::mpsc::UnboundedReceiver<Email>) -> Email {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("sent")
+            .expect("open")
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_scheduler_suppressions() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+        Suppression::add(
+            &mut conn,
+            &address("gone@example.com"),
+            SuppressionReason::HardBounce,
+            None,
+        )
+        .await
+        .expect("suppressed");
+        let pool = establish_connection_pool(&harness.db_conf.db_url(&harness.db_name), false)
+            .await
+            .expect("pool");
+
+        let templates_dir = std::env::temp_dir().join(format!("templates-{}", Uuid::new_v4()));
+        std::fs::create_dir(&templates_dir).expect("templates dir");
+        let (schedule_tx, schedule_rx) = broadcast::channel(10);
+        let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        let profile = RateLimitProfile {
+            max_rate: RateLimit {
+                rate_per_window: 10,
+                window: Duration::from_secs(1),
+            },
+            burst_rate: None,
+        };
+        EmailScheduler::new(
+            address("noreply@example.com"),
+            templates_dir.clone(),
+            schedule_rx,
+            profile,
+        )
+        .check_suppressions(Arc::new(pool))
+        .spawn(|mut rx| async move {
+            while let Some(email) = rx.recv().await {
+                sent_tx.send(email).ok();
+            }
+        });
+
+        let send = |email| schedule_tx.send(email).expect("scheduler running");
+        send(ScheduledEmail::new(
+            address("gone@example.com"),
+            TestTemplate,
+        ));
+        send(ScheduledEmail::new(address("gone@example.com"), TestTemplate).transactional());
+        send(
+            ScheduledEmail::new(address("ok@example.com"), TestTemplate)
+                .with_headers(EmailHeaders::default().bcc(address("gone@example.com"))),
+        );
+
+        let transactional = receive(&mut sent_rx).await;
+        assert_eq!(transactional.to, address("gone@example.com"));
+        let copied = receive(&mut sent_rx).await;
+        assert_eq!(copied.to, address("ok@example.com"));
+        assert!(copied.headers.bcc.is_empty());
+        std::fs::remove_dir_all(templates_dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_shutdown() {
+        let templates_dir = std::env::temp_dir().join(format!("templates-{}", Uuid::new_v4()));
+        std::fs::create_dir(&templates_dir).expect("templates dir");
+        let (schedule_tx, schedule_rx) = broadcast::channel(4);
+        let profile = RateLimitProfile {
+            max_rate: RateLimit {
+                rate_per_window: 10,
+                window: Duration::from_secs(1),
+            },
+            burst_rate: None,
+        };
+
+        // The scheduler starts behind: the first two emails are overwritten in the channel.
+        for to in [
+            "lost@example.com",
+            "lost@example.com",
+            "a@example.com",
+            "b@example.com",
+            "c@example.com",
+        ] {
+            schedule_tx
+                .send(ScheduledEmail::new(address(to), TestTemplate))
+                .expect("scheduler subscribed");
+        }
+        let later = Utc::now() + chrono::Duration::hours(1);
+        schedule_tx
+            .send(ScheduledEmail::new(address("later@example.com"), TestTemplate).send_at(later))
+            .expect("scheduler subscribed");
+
+        let capture = CaptureTransport::new();
+        let transport = capture.clone();
+        let handle = schedule_emails(
+            address("noreply@example.com"),
+            templates_dir.clone(),
+            schedule_rx,
+            |rx| run_transport(transport, rx, None),
+            profile,
+        );
+        let metrics = handle.metrics();
+        timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("drained");
+
+        let sent: Vec<_> = capture.emails().into_iter().map(|e| e.to).collect();
+        assert_eq!(
+            sent,
+            vec![
+                address("a@example.com"),
+                address("b@example.com"),
+                address("c@example.com")
+            ]
+        );
+        assert_eq!(metrics.skipped(), 2);
+        assert_eq!(metrics.received(), 4);
+        assert_eq!(metrics.rendered(), 3);
+        assert!(schedule_tx
+            .send(ScheduledEmail::new(address("a@example.com"), TestTemplate))
+            .is_err());
+        std::fs::remove_dir_all(templates_dir).ok();
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_outbox_dispatch() {
+        use crate::schema::auth::email_outbox;
+        use diesel::QueryDsl;
+        use diesel_async::RunQueryDsl;
+
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+        let pool = establish_connection_pool(&harness.db_conf.db_url(&harness.db_name), false)
+            .await
+            .expect("pool");
+        let queued = OutboxEmail::enqueue(
+            &mut conn,
+            &ScheduledEmail::new(address("ada@example.com"), TestTemplate),
+        )
+        .await
+        .expect("enqueued");
+
+        // Without a scheduler the email stays pending.
+        let (schedule_tx, schedule_rx) = broadcast::channel(10);
+        drop(schedule_rx);
+        let (reports_tx, reports_rx) = broadcast::channel(10);
+        let profile = OutboxProfile {
+            interval: Duration::from_millis(10),
+            ..OutboxProfile::default()
+        };
+        let dispatcher = dispatch_outbox::<TestTemplate>(
+            Arc::new(pool),
+            schedule_tx.clone(),
+            reports_rx,
+            profile,
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let row: OutboxEmail = email_outbox::table
+            .find(queued.id)
+            .first(&mut conn)
+            .await
+            .expect("row");
+        assert_eq!((row.status.as_str(), row.attempts), ("pending", 0));
+
+        // Once delivered, the report marks it sent.
+        let templates_dir = std::env::temp_dir().join(format!("templates-{}", Uuid::new_v4()));
+        std::fs::create_dir(&templates_dir).expect("templates dir");
+        let capture = CaptureTransport::new();
+        let transport = capture.clone();
+        let handle = schedule_emails(
+            address("noreply@example.com"),
+            templates_dir.clone(),
+            schedule_tx.subscribe(),
+            |rx| run_transport(transport, rx, Some(reports_tx)),
+            RateLimitProfile {
+                max_rate: RateLimit {
+                    rate_per_window: 10,
+                    window: Duration::from_secs(1),
+                },
+                burst_rate: None,
+            },
+        );
+        let sent = capture
+            .wait_for("ada@example.com", Duration::from_secs(5))
+            .await
+            .expect("sent");
+        assert_eq!(sent.outbox_ids, vec![queued.id]);
+        let mut status = String::new();
+        for _ in 0..100 {
+            status = email_outbox::table
+                .find(queued.id)
+                .select(email_outbox::status)
+                .first(&mut conn)
+                .await
+                .expect("row");
+            // The report may arrive before the dispatcher has committed the row as sending.
+            if status == "sent" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(status, "sent");
+
+        dispatcher.abort();
+        drop(schedule_tx);
+        timeout(Duration::from_secs(5), handle.wait())
+            .await
+            .expect("stopped");
+        std::fs::remove_dir_all(templates_dir).ok();
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use handlebars::html_escape;
+use serde_json::Value;
+
+use super::reload::Templates;
+use super::{partial_files, Email};
+
+/// The `Email`s most recently rendered by the scheduler, newest first.
+pub struct RecentEmails {
+    limit: usize,
+    emails: Mutex<VecDeque<(DateTime<Utc>, Email)>>,
+}
+
+impl RecentEmails {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            emails: Mutex::new(VecDeque::with_capacity(limit)),
+        })
+    }
+
+    pub fn record(&self, email: &Email) {
+        let mut emails = self.emails.lock().expect("recent emails lock poisoned");
+        if emails.len() == self.limit {
+            emails.pop_back();
+        }
+        emails.push_front((Utc::now(), email.clone()));
+    }
+
+    pub fn list(&self) -> Vec<(DateTime<Utc>, Email)> {
+        let emails = self.emails.lock().expect("recent emails lock poisoned");
+        emails.iter().cloned().collect()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Email> {
+        let emails = self.emails.lock().expect("recent emails lock poisoned");
+        emails.get(index).map(|(_, email)| email.clone())
+    }
+}
+
+/// A rendered preview and its content type.
+pub struct Preview {
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Preview {
+    fn html(body: String) -> Self {
+        Self {
+            content_type: "text/html; charset=utf-8",
+            body,
+        }
+    }
+
+    fn text(body: String) -> Self {
+        Self {
+            content_type: "text/plain; charset=utf-8",
+            body,
+        }
+    }
+}
+
+/// Renders templates and recently sent emails for the development preview routes. Never
+/// expose these in production: they show every email the scheduler renders.
+pub struct EmailPreview {
+    templates: Arc<Templates>,
+    recent: Arc<RecentEmails>,
+    fixtures_dir: Option<PathBuf>,
+}
+
+impl EmailPreview {
+    pub fn new(templates: Arc<Templates>, recent: Arc<RecentEmails>) -> Self {
+        Self {
+            templates,
+            recent,
+            fixtures_dir: None,
+        }
+    }
+
+    /// Render `GET` previews with the data in `<fixtures_dir>/<template>.json`.
+    pub fn fixtures_dir(mut self, dir: PathBuf) -> Self {
+        self.fixtures_dir = Some(dir);
+        self
+    }
+
+    /// The registered templates, without the partials and layouts they share a registry with.
+    pub fn template_names(&self) -> Vec<String> {
+        let partials = self.partials();
+        let mut names: Vec<_> = self
+            .templates
+            .get()
+            .get_templates()
+            .keys()
+            .filter(|name| !partials.contains(*name))
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn partials(&self) -> HashSet<String> {
+        partial_files(self.templates.dir())
+            .map(|partials| partials.into_iter().map(|(name, _)| name).collect())
+            .unwrap_or_default()
+    }
+
+    /// Render `template` with `data`, else with its fixture, else with no data.
+    pub fn render(&self, template: &str, data: Option<Value>) -> Result<Option<Preview>> {
+        let handlebars = self.templates.get();
+        if !handlebars.has_template(template) || self.partials().contains(template) {
+            return Ok(None);
+        }
+        let data = match data {
+            Some(data) => data,
+            None => self.fixture(template)?,
+        };
+        let body = handlebars.render(template, &data)?;
+        Ok(Some(if template.ends_with(".txt") {
+            Preview::text(body)
+        } else {
+            Preview::html(body)
+        }))
+    }
+
+    fn fixture(&self, template: &str) -> Result<Value> {
+        let Some(dir) = &self.fixtures_dir else {
+            return Ok(Value::Null);
+        };
+        let path = dir.join(format!("{}.json", template));
+        if !path.is_file() {
+            return Ok(Value::Null);
+        }
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// The HTML of the `index`th most recent email.
+    pub fn sent(&self, index: usize) -> Option<Preview> {
+        let email = self.recent.get(index)?;
+        Some(Preview::html(email.message.0))
+    }
+
+    /// A page linking to every template and recent email, relative to `base`.
+    pub fn index(&self, base: &str) -> Preview {
+        let mut page = String::from(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Email previews</title>\
+             </head><body><h1>Templates</h1><ul>",
+        );
+        for name in self.template_names() {
+            let name = html_escape(&name);
+            write!(
+                page,
+                "<li><a href=\"{}/templates/{}\">{}</a></li>",
+                base, name, name
+            )
+            .ok();
+        }
+        page.push_str("</ul><h1>Sent</h1><ul>");
+        for (i, (sent, email)) in self.recent.list().iter().enumerate() {
+            write!(
+                page,
+                "<li><a href=\"{}/sent/{}\">{}</a> to {} at {}</li>",
+                base,
+                i,
+                html_escape(&email.subject),
+                html_escape(email.to.as_str()),
+                sent.format("%Y-%m-%d %H:%M:%S")
+            )
+            .ok();
+        }
+        page.push_str("</ul></body></html>");
+        Preview::html(page)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use email_address::EmailAddress;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::email::FilledTemplate;
+
+    #[test]
+    fn test_email_preview() {
+        let dir = std::env::temp_dir().join(format!("templates-{}", Uuid::new_v4()));
+        let fixtures = dir.join("fixtures");
+        std::fs::create_dir_all(&fixtures).expect("templates dir");
+        std::fs::create_dir_all(dir.join("partials")).expect("partials dir");
+        std::fs::write(dir.join("partials/footer.html"), "<p>Bye</p>").expect("written");
+        std::fs::write(dir.join("welcome.html"), "<p>Hi {{name}}</p>").expect("written");
+        std::fs::write(dir.join("welcome.txt"), "Hi {{name}}").expect("written");
+        std::fs::write(fixtures.join("welcome.json"), r#"{"name": "Ada"}"#).expect("written");
+
+        let recent = RecentEmails::new(2);
+        let preview = EmailPreview::new(Templates::load(dir.clone()).unwrap(), recent.clone())
+            .fixtures_dir(fixtures);
+        assert_eq!(preview.template_names(), vec!["welcome", "welcome.txt"]);
+        let html = preview.render("welcome", None).unwrap().expect("template");
+        assert_eq!(html.body, "<p>Hi Ada</p>");
+        let text = preview
+            .render("welcome.txt", Some(json!({"name": "Grace"})))
+            .unwrap()
+            .expect("template");
+        assert_eq!(
+            (text.content_type, text.body.as_str()),
+            ("text/plain; charset=utf-8", "Hi Grace")
+        );
+        assert!(preview.render("missing", None).unwrap().is_none());
+        assert!(preview.render("footer", None).unwrap().is_none());
+
+        let from = EmailAddress::from_str("noreply@example.com").unwrap();
+        for subject in ["First", "Second", "Third"] {
+            let to = EmailAddress::from_str("ada@example.com").unwrap();
+            let body = FilledTemplate(format!("<p>{}</p>", subject));
+            recent.record(&Email::new(&to, &from, subject, body));
+        }
+        assert_eq!(preview.sent(0).expect("sent").body, "<p>Third</p>");
+        assert!(preview.sent(2).is_none());
+        let index = preview.index("/email/preview").body;
+        assert!(index.contains("href=\"/email/preview/templates/welcome.txt\""));
+        assert!(index.contains("href=\"/email/preview/sent/1\">Second</a>"));
+        assert!(!index.contains("First"));
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use handlebars::Handlebars;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use super::setup_handlebars;
+use crate::router::ChannelRouter;
+
+/// Announce on the `ChannelRouter` to reload every email template, e.g. from a SIGHUP handler.
+#[derive(Clone, Debug)]
+pub struct ReloadTemplates;
+
+/// The templates registered from a directory, replaced as a whole on reload so an email is
+/// never rendered with a mix of old and new templates.
+pub struct Templates {
+    dir: PathBuf,
+    current: RwLock<Arc<Handlebars<'static>>>,
+}
+
+impl Templates {
+    pub fn load(dir: PathBuf) -> Result<Arc<Self>> {
+        let handlebars = setup_handlebars(&dir)?;
+        Ok(Arc::new(Self {
+            dir,
+            current: RwLock::new(Arc::new(handlebars)),
+        }))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The templates as of the last successful load.
+    pub fn get(&self) -> Arc<Handlebars<'static>> {
+        self.current
+            .read()
+            .expect("templates lock poisoned")
+            .clone()
+    }
+
+    /// Register the directory again. If any template fails to parse the previous templates
+    /// stay active.
+    pub fn reload(&self) -> Result<()> {
+        match setup_handlebars(&self.dir) {
+            Ok(handlebars) => {
+                *self.current.write().expect("templates lock poisoned") = Arc::new(handlebars);
+                tracing::info!("Reloaded email templates from {}", self.dir.display());
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Keeping the previous email templates, reloading {} failed: {}",
+                    self.dir.display(),
+                    err
+                );
+                Err(err)
+            }
+        }
+    }
+
+    /// Poll the directory every `period` and reload when a template changes. Meant for
+    /// development. Stops once the templates are dropped.
+    pub fn watch(self: &Arc<Self>, period: Duration) {
+        let templates = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticks = interval(period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticks.tick().await;
+            let mut last = match templates.upgrade() {
+                Some(templates) => fingerprint(&templates.dir),
+                None => return,
+            };
+            loop {
+                ticks.tick().await;
+                let Some(templates) = templates.upgrade() else {
+                    break;
+                };
+                let current = fingerprint(&templates.dir);
+                if current != last {
+                    last = current;
+                    templates.reload().ok();
+                }
+            }
+        });
+    }
+
+    /// Reload whenever `ReloadTemplates` is announced on `router`. Stops once the templates
+    /// are dropped.
+    pub fn reload_on(self: &Arc<Self>, router: &ChannelRouter) {
+        let templates = Arc::downgrade(self);
+        let mut rx = router.subscribe::<ReloadTemplates>();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(ReloadTemplates) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+                match Weak::upgrade(&templates) {
+                    Some(templates) => templates.reload().ok(),
+                    None => break,
+                };
+            }
+        });
+    }
+}
+
+/// The path, modification time and size of every file under `dir`.
+fn fingerprint(dir: &Path) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push((path, metadata.modified().ok(), metadata.len()));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn render(templates: &Templates) -> String {
+        templates
+            .get()
+            .render("greeting", &json!({"name": "Ada"}))
+            .expect("rendered")
+    }
+
+    #[tokio::test]
+    async fn test_reload_templates() {
+        let dir = std::env::temp_dir().join(format!("templates-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).expect("templates dir");
+        let template = dir.join("greeting.html");
+        std::fs::write(&template, "Hello {{name}}").expect("written");
+        let templates = Templates::load(dir.clone()).expect("loaded");
+        assert_eq!(render(&templates), "Hello Ada");
+
+        std::fs::write(&template, "Hello {{#if name}}").expect("written");
+        assert!(templates.reload().is_err());
+        assert_eq!(render(&templates), "Hello Ada");
+
+        let router = ChannelRouter::new();
+        templates.reload_on(&router);
+        std::fs::write(&template, "Hi {{name}}").expect("written");
+        router.announce().send(ReloadTemplates).expect("subscribed");
+        for _ in 0..50 {
+            if render(&templates) != "Hello Ada" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(render(&templates), "Hi Ada");
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
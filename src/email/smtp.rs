@@ -0,0 +1,675 @@
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::TlsConnector;
+
+use super::dkim::DkimSigner;
+use super::mime::MimeBuilder;
+use super::transport::{EmailTransport, TransportError};
+use super::Email;
+use crate::server::EnvFilledConfig;
+use crate::tables::root_certs;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plaintext. Only for local relays and testing.
+    None,
+    /// Upgrade a plaintext connection with STARTTLS, usually on port 587.
+    #[default]
+    StartTls,
+    /// TLS from the start of the connection, usually on port 465.
+    Tls,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpAuth {
+    #[default]
+    Plain,
+    Login,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub auth: SmtpAuth,
+    /// The name sent with EHLO, "localhost" if unset.
+    pub hello_name: Option<String>,
+}
+
+impl EnvFilledConfig for SmtpConfig {
+    fn fill_from_env(self) -> Result<Self, env::VarError> {
+        Ok(Self {
+            username: env::var("SMTP_USERNAME").ok().or(self.username),
+            password: env::var("SMTP_PASSWORD").ok().or(self.password),
+            ..self
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum SmtpError {
+    Io(std::io::Error),
+    Timeout,
+    /// The server replied with an error code.
+    Reply {
+        code: u16,
+        message: String,
+    },
+    /// The server doesn't support something the configuration requires.
+    Unsupported(String),
+    /// The message could not be DKIM signed.
+    Dkim(anyhow::Error),
+}
+
+impl SmtpError {
+    /// Whether retrying the same message can't succeed.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, SmtpError::Reply { code, .. } if *code >= 500)
+            || matches!(self, SmtpError::Unsupported(_) | SmtpError::Dkim(_))
+    }
+}
+
+impl fmt::Display for SmtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtpError::Io(err) => write!(f, "SMTP connection error: {}", err),
+            SmtpError::Timeout => write!(f, "SMTP server timed out"),
+            SmtpError::Reply { code, message } => write!(f, "SMTP error {}: {}", code, message),
+            SmtpError::Unsupported(what) => write!(f, "SMTP server does not support {}", what),
+            SmtpError::Dkim(err) => write!(f, "DKIM signing failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SmtpError {}
+
+impl From<std::io::Error> for SmtpError {
+    fn from(err: std::io::Error) -> Self {
+        SmtpError::Io(err)
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    fn message(&self) -> String {
+        self.lines.join(" ")
+    }
+
+    fn has_extension(&self, extension: &str) -> bool {
+        // The first line is the greeting; each following line names an extension.
+        self.lines.iter().skip(1).any(|line| {
+            line.split_whitespace()
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case(extension))
+        })
+    }
+
+    fn auth_mechanisms(&self) -> Vec<String> {
+        self.lines
+            .iter()
+            .skip(1)
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                match words.next() {
+                    Some(name) if name.eq_ignore_ascii_case("AUTH") => {
+                        Some(words.map(|w| w.to_ascii_uppercase()).collect::<Vec<_>>())
+                    }
+                    _ => None,
+                }
+            })
+            .flatten()
+            .collect()
+    }
+}
+
+struct SmtpConnection {
+    stream: BufReader<Box<dyn Io>>,
+}
+
+impl SmtpConnection {
+    async fn connect(
+        config: &SmtpConfig,
+        tls_config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self, SmtpError> {
+        let tcp = timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect((config.host.as_str(), config.port)),
+        )
+        .await
+        .map_err(|_| SmtpError::Timeout)??;
+        let stream: Box<dyn Io> = match config.security {
+            SmtpSecurity::Tls => Box::new(Self::tls(config, tls_config.clone(), tcp).await?),
+            _ => Box::new(tcp),
+        };
+        let mut conn = Self {
+            stream: BufReader::new(stream),
+        };
+        conn.expect(220).await?;
+        let hello_name = config.hello_name.as_deref().unwrap_or("localhost");
+        let mut ehlo = conn.command(&format!("EHLO {}", hello_name), 250).await?;
+
+        if config.security == SmtpSecurity::StartTls {
+            if !ehlo.has_extension("STARTTLS") {
+                return Err(SmtpError::Unsupported("STARTTLS".to_string()));
+            }
+            conn.command("STARTTLS", 220).await?;
+            // Anything already buffered was sent before the handshake and can't be trusted.
+            if !conn.stream.buffer().is_empty() {
+                return Err(SmtpError::Unsupported(
+                    "pipelining before STARTTLS".to_string(),
+                ));
+            }
+            let tcp = conn.stream.into_inner();
+            let tls: Box<dyn Io> = Box::new(Self::tls(config, tls_config, tcp).await?);
+            conn = Self {
+                stream: BufReader::new(tls),
+            };
+            ehlo = conn.command(&format!("EHLO {}", hello_name), 250).await?;
+        }
+
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            conn.authenticate(config.auth, &ehlo, username, password)
+                .await?;
+        }
+        Ok(conn)
+    }
+
+    async fn tls<S: AsyncRead + AsyncWrite + Unpin>(
+        config: &SmtpConfig,
+        tls_config: Arc<rustls::ClientConfig>,
+        stream: S,
+    ) -> Result<tokio_rustls::client::TlsStream<S>, SmtpError> {
+        let connector = TlsConnector::from(tls_config);
+        let server_name = ServerName::try_from(config.host.clone())
+            .map_err(|err| SmtpError::Io(std::io::Error::other(err)))?;
+        Ok(connector.connect(server_name, stream).await?)
+    }
+
+    async fn authenticate(
+        &mut self,
+        auth: SmtpAuth,
+        ehlo: &Reply,
+        username: &str,
+        password: &str,
+    ) -> Result<(), SmtpError> {
+        let mechanisms = ehlo.auth_mechanisms();
+        match auth {
+            SmtpAuth::Plain => {
+                if !mechanisms.iter().any(|m| m == "PLAIN") {
+                    return Err(SmtpError::Unsupported("AUTH PLAIN".to_string()));
+                }
+                let credentials = base64::encode(format!("\0{}\0{}", username, password));
+                self.command(&format!("AUTH PLAIN {}", credentials), 235)
+                    .await?;
+            }
+            SmtpAuth::Login => {
+                if !mechanisms.iter().any(|m| m == "LOGIN") {
+                    return Err(SmtpError::Unsupported("AUTH LOGIN".to_string()));
+                }
+                self.command("AUTH LOGIN", 334).await?;
+                self.command(&base64::encode(username), 334).await?;
+                self.command(&base64::encode(password), 235).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply, SmtpError> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            let read = timeout(REPLY_TIMEOUT, self.stream.read_line(&mut line))
+                .await
+                .map_err(|_| SmtpError::Timeout)??;
+            if read == 0 {
+                return Err(SmtpError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| SmtpError::Reply {
+                    code: 0,
+                    message: format!("malformed reply: {}", line),
+                })?;
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            // "250-" continues a multiline reply, "250 " ends it.
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+
+    async fn expect(&mut self, code: u16) -> Result<Reply, SmtpError> {
+        let reply = self.read_reply().await?;
+        if reply.code != code {
+            return Err(SmtpError::Reply {
+                code: reply.code,
+                message: reply.message(),
+            });
+        }
+        Ok(reply)
+    }
+
+    async fn command(&mut self, command: &str, code: u16) -> Result<Reply, SmtpError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        self.expect(code).await
+    }
+
+    async fn send(
+        &mut self,
+        envelope_from: &str,
+        recipients: &[String],
+        message: &str,
+    ) -> Result<(), SmtpError> {
+        let result = self.transaction(envelope_from, recipients, message).await;
+        if let Err(SmtpError::Reply { .. }) = &result {
+            // Clear the failed transaction so the connection can be reused.
+            self.command("RSET", 250).await?;
+        }
+        result
+    }
+
+    async fn transaction(
+        &mut self,
+        envelope_from: &str,
+        recipients: &[String],
+        message: &str,
+    ) -> Result<(), SmtpError> {
+        self.command(&format!("MAIL FROM:<{}>", envelope_from), 250)
+            .await?;
+        for (i, to) in recipients.iter().enumerate() {
+            let reply = self.command(&format!("RCPT TO:<{}>", to), 250).await;
+            match reply {
+                Ok(_) | Err(SmtpError::Reply { code: 251, .. }) => {}
+                // Only the recipient itself is required, copies the server refuses are dropped.
+                Err(SmtpError::Reply { code, message }) if i > 0 && code >= 500 => {
+                    tracing::warn!("Not sending a copy to {}: {} {}", to, code, message);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.command("DATA", 354).await?;
+        let stream = self.stream.get_mut();
+        stream.write_all(dot_stuff(message).as_bytes()).await?;
+        stream.write_all(b"\r\n.\r\n").await?;
+        stream.flush().await?;
+        self.expect(250).await?;
+        Ok(())
+    }
+
+    async fn quit(mut self) {
+        self.command("QUIT", 221).await.ok();
+    }
+}
+
+/// Lines starting with a dot would end the DATA section early, so they get a second dot.
+fn dot_stuff(message: &str) -> String {
+    let mut stuffed = String::with_capacity(message.len());
+    for (i, line) in message.split("\r\n").enumerate() {
+        if i > 0 {
+            stuffed.push_str("\r\n");
+        }
+        if line.starts_with('.') {
+            stuffed.push('.');
+        }
+        stuffed.push_str(line);
+    }
+    stuffed
+}
+
+/// Sends emails over SMTP, keeping the connection open between emails that arrive close together.
+pub struct SmtpTransport {
+    config: SmtpConfig,
+    connection: Option<SmtpConnection>,
+    dkim: Option<Arc<DkimSigner>>,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl SmtpTransport {
+    pub fn new(config: SmtpConfig) -> Self {
+        Self {
+            config,
+            connection: None,
+            dkim: None,
+            tls_config: None,
+        }
+    }
+
+    /// DKIM sign every message just before it is sent.
+    pub fn dkim(self, signer: Arc<DkimSigner>) -> Self {
+        Self {
+            dkim: Some(signer),
+            ..self
+        }
+    }
+
+    /// Verify the server with `tls_config` instead of the native roots and those added with
+    /// `init_cert_pool`, e.g. for a relay with a private CA.
+    pub fn tls_config(self, tls_config: Arc<rustls::ClientConfig>) -> Self {
+        Self {
+            tls_config: Some(tls_config),
+            ..self
+        }
+    }
+
+    /// Send one email, connecting if needed. A connection the server has dropped is replaced
+    /// once before giving up.
+    pub async fn send(&mut self, email: &Email) -> Result<(), SmtpError> {
+        let mut message = MimeBuilder::new(email).build();
+        if let Some(signer) = &self.dkim {
+            message = signer.sign(&message).map_err(SmtpError::Dkim)?;
+        }
+        let from = email.from.to_string();
+        let recipients: Vec<_> = email.recipients().map(|to| to.to_string()).collect();
+        let tls_config = self
+            .tls_config
+            .get_or_insert_with(|| {
+                Arc::new(
+                    rustls::ClientConfig::builder()
+                        .with_root_certificates(root_certs())
+                        .with_no_client_auth(),
+                )
+            })
+            .clone();
+        let reused = self.connection.is_some();
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => SmtpConnection::connect(&self.config, tls_config.clone()).await?,
+        };
+        let mut result = connection.send(&from, &recipients, &message).await;
+        if reused && matches!(result, Err(SmtpError::Io(_)) | Err(SmtpError::Timeout)) {
+            connection = SmtpConnection::connect(&self.config, tls_config).await?;
+            result = connection.send(&from, &recipients, &message).await;
+        }
+        if !matches!(result, Err(SmtpError::Io(_)) | Err(SmtpError::Timeout)) {
+            self.connection = Some(connection);
+        }
+        result
+    }
+
+    /// Close the connection, if one is open.
+    pub async fn close(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            connection.quit().await;
+        }
+    }
+}
+
+impl EmailTransport for SmtpTransport {
+    async fn send(&mut self, email: &Email) -> Result<(), TransportError> {
+        SmtpTransport::send(self, email)
+            .await
+            .map_err(TransportError::Smtp)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    async fn close(&mut self) {
+        SmtpTransport::close(self).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use email_address::EmailAddress;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::email::headers::EmailHeaders;
+    use crate::email::test::email;
+
+    /// A scripted SMTP server which accepts AUTH PLAIN or LOGIN, rejects one recipient and
+    /// returns after the client quits. `acceptor` secures the connection for `security`.
+    async fn stub_server(
+        listener: TcpListener,
+        security: SmtpSecurity,
+        acceptor: Option<TlsAcceptor>,
+        received: mpsc::UnboundedSender<String>,
+    ) {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut secure = security == SmtpSecurity::Tls;
+        let stream: Box<dyn Io> = if secure {
+            Box::new(acceptor.clone().unwrap().accept(tcp).await.unwrap())
+        } else {
+            Box::new(tcp)
+        };
+        let mut stream = BufReader::new(stream);
+        stream
+            .get_mut()
+            .write_all(b"220 stub ready\r\n")
+            .await
+            .unwrap();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                panic!("connection closed without QUIT");
+            }
+            let command = line.trim_end().to_string();
+            let reply: &[u8] = if command.starts_with("EHLO") {
+                if security == SmtpSecurity::StartTls && !secure {
+                    b"250-stub\r\n250 STARTTLS\r\n"
+                } else {
+                    b"250-stub\r\n250-8BITMIME\r\n250 AUTH PLAIN LOGIN\r\n"
+                }
+            } else if command == "STARTTLS" {
+                stream
+                    .get_mut()
+                    .write_all(b"220 go ahead\r\n")
+                    .await
+                    .unwrap();
+                let tcp = stream.into_inner();
+                let tls = acceptor.clone().unwrap().accept(tcp).await.unwrap();
+                stream = BufReader::new(Box::new(tls));
+                secure = true;
+                continue;
+            } else if command.starts_with("AUTH PLAIN") {
+                let credentials = base64::decode(&command[11..]).unwrap();
+                assert_eq!(credentials, b"\0user\0secret");
+                b"235 ok\r\n"
+            } else if command == "AUTH LOGIN" {
+                assert_eq!(secure, security != SmtpSecurity::None);
+                for (prompt, expected) in [
+                    (b"334 VXNlcm5hbWU6\r\n", "user"),
+                    (b"334 UGFzc3dvcmQ6\r\n", "secret"),
+                ] {
+                    stream.get_mut().write_all(prompt).await.unwrap();
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    assert_eq!(
+                        base64::decode(line.trim_end()).unwrap(),
+                        expected.as_bytes()
+                    );
+                }
+                b"235 ok\r\n"
+            } else if command == "RCPT TO:<bounce@example.com>" {
+                b"550 no such user\r\n"
+            } else if command == "DATA" {
+                stream
+                    .get_mut()
+                    .write_all(b"354 go ahead\r\n")
+                    .await
+                    .unwrap();
+                let mut data = vec![];
+                while !data.ends_with(b"\r\n.\r\n") {
+                    data.push(stream.read_u8().await.unwrap());
+                }
+                received.send(String::from_utf8(data).unwrap()).unwrap();
+                b"250 queued\r\n"
+            } else if command == "QUIT" {
+                stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                return;
+            } else {
+                b"250 ok\r\n"
+            };
+            stream.get_mut().write_all(reply).await.unwrap();
+        }
+    }
+
+    /// A server config for a self-signed "localhost" certificate and a client config that
+    /// trusts it.
+    fn self_signed() -> (TlsAcceptor, Arc<rustls::ClientConfig>) {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+        let server = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key.into())
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let client = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (TlsAcceptor::from(Arc::new(server)), Arc::new(client))
+    }
+
+    #[tokio::test]
+    async fn test_smtp_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+        let stub = tokio::spawn(stub_server(listener, SmtpSecurity::None, None, received_tx));
+
+        let mut transport = SmtpTransport::new(SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            auth: SmtpAuth::Plain,
+            hello_name: None,
+        });
+
+        transport
+            .send(&email(
+                "test@example.com",
+                "Héllo",
+                "<p>Hi</p>\r\n.leading dot",
+            ))
+            .await
+            .unwrap();
+        let message = received_rx.recv().await.unwrap();
+        assert!(message.contains("Subject: =?UTF-8?B?SMOpbGxv?=\r\n"));
+        assert!(message.contains("To: test@example.com\r\n"));
+        assert!(message.contains("<p>Hi</p>\r\n..leading dot\r\n"));
+
+        let err = transport
+            .send(&email("bounce@example.com", "Hello", "<p>Hi</p>"))
+            .await
+            .unwrap_err();
+        assert!(err.is_permanent());
+
+        // The rejected recipient doesn't spoil the connection for the next email, and a
+        // rejected copy doesn't stop the email.
+        let copied = email("again@example.com", "Hello", "<p>Hi</p>").with_headers(
+            EmailHeaders::default().bcc(EmailAddress::from_str("bounce@example.com").unwrap()),
+        );
+        transport.send(&copied).await.unwrap();
+        assert!(received_rx.recv().await.is_some());
+        transport.close().await;
+        stub.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_smtp_transport_tls() {
+        for security in [SmtpSecurity::StartTls, SmtpSecurity::Tls] {
+            let (acceptor, client) = self_signed();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+            let stub = tokio::spawn(stub_server(listener, security, Some(acceptor), received_tx));
+
+            let mut transport = SmtpTransport::new(SmtpConfig {
+                host: "localhost".to_string(),
+                port,
+                security,
+                username: Some("user".to_string()),
+                password: Some("secret".to_string()),
+                auth: SmtpAuth::Login,
+                hello_name: None,
+            })
+            .tls_config(client);
+
+            transport
+                .send(&email("test@example.com", "Hello", "<p>Hi</p>"))
+                .await
+                .unwrap();
+            let message = received_rx.recv().await.unwrap();
+            assert!(message.contains("To: test@example.com\r\n"));
+            transport.close().await;
+            stub.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smtp_transport_untrusted() {
+        let (acceptor, _) = self_signed();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (received_tx, _received_rx) = mpsc::unbounded_channel();
+        let stub = tokio::spawn(stub_server(
+            listener,
+            SmtpSecurity::Tls,
+            Some(acceptor),
+            received_tx,
+        ));
+
+        let mut transport = SmtpTransport::new(SmtpConfig {
+            host: "localhost".to_string(),
+            port,
+            security: SmtpSecurity::Tls,
+            username: None,
+            password: None,
+            auth: SmtpAuth::Plain,
+            hello_name: None,
+        })
+        .tls_config(self_signed().1);
+        assert!(transport
+            .send(&email("test@example.com", "Hello", "<p>Hi</p>"))
+            .await
+            .is_err());
+        // The stub's handshake fails too.
+        assert!(stub.await.is_err());
+    }
+
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(dot_stuff(".a\r\nb\r\n..c"), "..a\r\nb\r\n...c");
+    }
+}
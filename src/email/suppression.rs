@@ -0,0 +1,256 @@
+use std::str::FromStr;
+
+use diesel_async::AsyncPgConnection;
+use email_address::EmailAddress;
+use serde_json::Value;
+
+use crate::tables::{constant_time_eq, Suppression, SuppressionReason};
+
+const MIN_SECRET_LEN: usize = 16;
+
+/// Whose bounce and complaint notifications a webhook receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookProvider {
+    /// Amazon SES notifications delivered by SNS, or SES event publishing.
+    Ses,
+    SendGrid,
+    Postmark,
+}
+
+impl FromStr for WebhookProvider {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ses" => Ok(Self::Ses),
+            "sendgrid" => Ok(Self::SendGrid),
+            "postmark" => Ok(Self::Postmark),
+            _ => anyhow::bail!("Unknown webhook provider: {}", s),
+        }
+    }
+}
+
+/// The shared secret webhook URLs must carry as `?token=`, since providers can't sign in.
+pub struct WebhookSecret(String);
+
+impl WebhookSecret {
+    pub fn new(secret: &str) -> anyhow::Result<Self> {
+        if secret.len() < MIN_SECRET_LEN {
+            anyhow::bail!("Webhook secret must be at least {} bytes", MIN_SECRET_LEN);
+        }
+        Ok(Self(secret.to_string()))
+    }
+
+    pub fn verify(&self, token: &str) -> bool {
+        constant_time_eq(&self.0, token)
+    }
+}
+
+/// An address to stop emailing, parsed from a provider notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuppressionEvent {
+    pub email: EmailAddress,
+    pub reason: SuppressionReason,
+    pub detail: Option<String>,
+}
+
+impl SuppressionEvent {
+    fn new(email: &Value, reason: SuppressionReason, detail: Option<&Value>) -> Option<Self> {
+        let email = EmailAddress::from_str(email.as_str()?).ok()?;
+        Some(Self {
+            email,
+            reason,
+            detail: detail.and_then(Value::as_str).map(str::to_string),
+        })
+    }
+}
+
+/// Parse the hard bounces and complaints from a webhook body. Soft bounces and other events
+/// are ignored.
+pub fn parse_webhook(provider: WebhookProvider, body: &Value) -> Vec<SuppressionEvent> {
+    match provider {
+        WebhookProvider::Ses => parse_ses(body),
+        WebhookProvider::SendGrid => parse_sendgrid(body),
+        WebhookProvider::Postmark => parse_postmark(body),
+    }
+}
+
+fn parse_ses(body: &Value) -> Vec<SuppressionEvent> {
+    // SNS wraps the SES notification as a JSON string.
+    let message = match body["Type"].as_str() {
+        Some("Notification") => match body["Message"].as_str().map(serde_json::from_str) {
+            Some(Ok(message)) => message,
+            _ => return vec![],
+        },
+        Some("SubscriptionConfirmation") => {
+            tracing::warn!(
+                "Confirm the SES notification subscription by visiting {}",
+                body["SubscribeURL"].as_str().unwrap_or_default()
+            );
+            return vec![];
+        }
+        Some(_) => return vec![],
+        None => body.clone(),
+    };
+    let kind = message["notificationType"]
+        .as_str()
+        .or_else(|| message["eventType"].as_str());
+    match kind {
+        Some("Bounce") if message["bounce"]["bounceType"] == "Permanent" => {
+            recipients(&message["bounce"]["bouncedRecipients"])
+                .filter_map(|recipient| {
+                    SuppressionEvent::new(
+                        &recipient["emailAddress"],
+                        SuppressionReason::HardBounce,
+                        recipient.get("diagnosticCode"),
+                    )
+                })
+                .collect()
+        }
+        Some("Complaint") => recipients(&message["complaint"]["complainedRecipients"])
+            .filter_map(|recipient| {
+                SuppressionEvent::new(
+                    &recipient["emailAddress"],
+                    SuppressionReason::Complaint,
+                    message["complaint"].get("complaintFeedbackType"),
+                )
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn recipients(value: &Value) -> impl Iterator<Item = &Value> {
+    value.as_array().into_iter().flatten()
+}
+
+fn parse_sendgrid(body: &Value) -> Vec<SuppressionEvent> {
+    // SendGrid posts a batch of events.
+    recipients(body)
+        .filter_map(|event| match event["event"].as_str() {
+            // "blocked" bounces are temporary refusals.
+            Some("bounce") if event["type"] != "blocked" => SuppressionEvent::new(
+                &event["email"],
+                SuppressionReason::HardBounce,
+                event.get("reason"),
+            ),
+            Some("spamreport") => {
+                SuppressionEvent::new(&event["email"], SuppressionReason::Complaint, None)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn parse_postmark(body: &Value) -> Vec<SuppressionEvent> {
+    let event = match body["RecordType"].as_str() {
+        Some("Bounce") => match body["Type"].as_str() {
+            Some("HardBounce" | "BadEmailAddress") => SuppressionEvent::new(
+                &body["Email"],
+                SuppressionReason::HardBounce,
+                body.get("Description"),
+            ),
+            _ => None,
+        },
+        Some("SpamComplaint") => {
+            SuppressionEvent::new(&body["Email"], SuppressionReason::Complaint, None)
+        }
+        _ => None,
+    };
+    event.into_iter().collect()
+}
+
+/// Suppress every address a webhook body reports. Returns how many were suppressed.
+pub async fn record_webhook(
+    conn: &mut AsyncPgConnection,
+    provider: WebhookProvider,
+    body: &Value,
+) -> anyhow::Result<usize> {
+    let events = parse_webhook(provider, body);
+    for event in &events {
+        tracing::info!("Suppressing {}: {}", event.email, event.reason);
+        Suppression::add(conn, &event.email, event.reason, event.detail.as_deref()).await?;
+    }
+    Ok(events.len())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn emails(events: Vec<SuppressionEvent>) -> Vec<(String, SuppressionReason)> {
+        events
+            .into_iter()
+            .map(|event| (event.email.to_string(), event.reason))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_webhooks() {
+        let ses_bounce = json!({
+            "notificationType": "Bounce",
+            "bounce": {
+                "bounceType": "Permanent",
+                "bouncedRecipients": [{"emailAddress": "gone@example.com", "diagnosticCode": "550"}],
+            },
+        });
+        let sns = json!({"Type": "Notification", "Message": ses_bounce.to_string()});
+        assert_eq!(
+            emails(parse_webhook(WebhookProvider::Ses, &sns)),
+            vec![(
+                "gone@example.com".to_string(),
+                SuppressionReason::HardBounce
+            )]
+        );
+        let ses_transient = json!({
+            "eventType": "Bounce",
+            "bounce": {"bounceType": "Transient", "bouncedRecipients": [{"emailAddress": "full@example.com"}]},
+        });
+        assert!(parse_webhook(WebhookProvider::Ses, &ses_transient).is_empty());
+        let ses_complaint = json!({
+            "notificationType": "Complaint",
+            "complaint": {"complainedRecipients": [{"emailAddress": "angry@example.com"}]},
+        });
+        assert_eq!(
+            emails(parse_webhook(WebhookProvider::Ses, &ses_complaint)),
+            vec![(
+                "angry@example.com".to_string(),
+                SuppressionReason::Complaint
+            )]
+        );
+
+        let sendgrid = json!([
+            {"email": "gone@example.com", "event": "bounce", "type": "bounce"},
+            {"email": "later@example.com", "event": "bounce", "type": "blocked"},
+            {"email": "angry@example.com", "event": "spamreport"},
+            {"email": "happy@example.com", "event": "delivered"},
+        ]);
+        assert_eq!(
+            emails(parse_webhook(WebhookProvider::SendGrid, &sendgrid)),
+            vec![
+                (
+                    "gone@example.com".to_string(),
+                    SuppressionReason::HardBounce
+                ),
+                (
+                    "angry@example.com".to_string(),
+                    SuppressionReason::Complaint
+                ),
+            ]
+        );
+
+        let postmark =
+            json!({"RecordType": "Bounce", "Type": "HardBounce", "Email": "gone@example.com"});
+        assert_eq!(parse_webhook(WebhookProvider::Postmark, &postmark).len(), 1);
+        let postmark_soft =
+            json!({"RecordType": "Bounce", "Type": "SoftBounce", "Email": "full@example.com"});
+        assert!(parse_webhook(WebhookProvider::Postmark, &postmark_soft).is_empty());
+
+        let secret = WebhookSecret::new("0123456789abcdef").expect("long enough");
+        assert!(secret.verify("0123456789abcdef"));
+        assert!(!secret.verify("0123456789abcdeF"));
+        assert!(WebhookSecret::new("short").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+
+use handlebars::Handlebars;
+
+use super::EmailTemplate;
+
+/// A sample email which could not be rendered.
+#[derive(Debug, Clone)]
+pub struct TemplateFailure {
+    /// The subject of the sample, to tell samples apart.
+    pub sample: String,
+    pub error: String,
+}
+
+/// Every sample email which failed to render, e.g. because its template is missing or it uses
+/// a variable the sample data doesn't have.
+#[derive(Debug, Clone)]
+pub struct TemplateValidationError {
+    pub failures: Vec<TemplateFailure>,
+}
+
+impl fmt::Display for TemplateValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} email templates failed to render:",
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            write!(f, "\n  {}: {}", failure.sample, failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TemplateValidationError {}
+
+/// Render the HTML and text of every `EmailTemplate::samples` in strict mode, where a missing
+/// variable is an error rather than an empty string. Call this at startup so broken templates
+/// fail fast instead of when the first email is sent.
+pub fn validate_templates<T: EmailTemplate>(
+    handlebars: &Handlebars,
+) -> Result<(), TemplateValidationError> {
+    let samples = T::samples();
+    if samples.is_empty() {
+        tracing::warn!(
+            "{} declares no samples, its templates are not validated",
+            std::any::type_name::<T>()
+        );
+    }
+    let mut strict = handlebars.clone();
+    strict.set_strict_mode(true);
+
+    let mut failures = vec![];
+    for sample in samples {
+        let subject = sample.subject();
+        let mut fail = |error: anyhow::Error| {
+            failures.push(TemplateFailure {
+                sample: subject.clone(),
+                error: format!("{:#}", error),
+            })
+        };
+        if let Err(err) = sample.fill_text(&strict) {
+            fail(err);
+        }
+        if let Err(err) = sample.fill(&strict) {
+            fail(err);
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(TemplateValidationError { failures })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Serialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::email::FilledTemplate;
+
+    #[derive(Clone, Debug, Serialize)]
+    struct Welcome {
+        template: String,
+        name: String,
+    }
+
+    impl EmailTemplate for Welcome {
+        fn subject(&self) -> String {
+            format!("Welcome via {}", self.template)
+        }
+
+        fn fill(self, handlebars: &Handlebars) -> anyhow::Result<FilledTemplate> {
+            FilledTemplate::new(handlebars, &self.template, &json!({"name": self.name}))
+        }
+
+        fn samples() -> Vec<Self> {
+            ["welcome", "welcome_typo", "welcome_extra"]
+                .into_iter()
+                .map(|template| Self {
+                    template: template.to_string(),
+                    name: "Ada".to_string(),
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_validate_templates() {
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string("welcome", "Hello {{name}}")
+            .unwrap();
+        handlebars
+            .register_template_string("welcome_extra", "Hello {{name}} from {{team}}")
+            .unwrap();
+
+        let err = validate_templates::<Welcome>(&handlebars).expect_err("invalid samples");
+        let failed: Vec<_> = err.failures.iter().map(|f| f.sample.as_str()).collect();
+        assert_eq!(
+            failed,
+            vec!["Welcome via welcome_typo", "Welcome via welcome_extra"]
+        );
+        let report = err.to_string();
+        assert!(report.contains("welcome_typo"), "{}", report);
+        assert!(report.contains("team"), "{}", report);
+
+        // Outside of validation, missing variables still render empty.
+        let lenient = Welcome {
+            template: "welcome_extra".to_string(),
+            name: "Ada".to_string(),
+        };
+        assert_eq!(lenient.fill(&handlebars).unwrap().0, "Hello Ada from ");
+    }
+}
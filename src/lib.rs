@@ -1,6 +1,7 @@
 pub mod api;
 pub mod email;
 pub mod oidc;
+pub mod pg_notify;
 pub mod rate_limit;
 pub mod router;
 mod rustls;
@@ -15,22 +15,14 @@ use reqwest::{redirect::Policy, Client};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::get_cert_pool;
+use crate::rustls::http_client_builder;
 
 fn new_client() -> Client {
-    let mut builder = Client::builder()
-        .use_rustls_tls()
+    http_client_builder()
         .https_only(true)
         .redirect(Policy::none())
-        .tcp_nodelay(true)
-        .tls_built_in_root_certs(true);
-
-    if let Some(cert_pool) = get_cert_pool() {
-        for cert in cert_pool.certs().iter() {
-            builder = builder.add_root_certificate(cert.clone());
-        }
-    }
-    builder.build().unwrap()
+        .build()
+        .unwrap()
 }
 
 pub async fn async_http_client(
@@ -0,0 +1,329 @@
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+use tokio_postgres::{AsyncMessage, Client, NoTls, Notification};
+use uuid::Uuid;
+
+use crate::router::ChannelRouter;
+use crate::tables::root_certs;
+
+/// Postgres rejects NOTIFY payloads of 8000 bytes or more.
+const MAX_PAYLOAD_BYTES: usize = 7999;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+type MessageStream = BoxStream<'static, Result<AsyncMessage, tokio_postgres::Error>>;
+type Announcer = Box<
+    dyn Fn(&ChannelRouter, &EchoFilter, serde_json::Value) -> serde_json::Result<()> + Send + Sync,
+>;
+type Forwarder = Box<
+    dyn Fn(Arc<Client>, ChannelRouter, Uuid, EchoFilter) -> BoxFuture<'static, ()> + Send + Sync,
+>;
+
+/// Every NOTIFY carries the id of the bridge that sent it so a bridge can ignore its own messages.
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+    origin: Uuid,
+    message: M,
+}
+
+/// Messages received from Postgres are announced on the local router, where the forwarder for
+/// that type would see them again. Recording the payloads the bridge announced lets the
+/// forwarder skip them instead of bouncing them between replicas forever.
+#[derive(Clone, Default)]
+struct EchoFilter {
+    forwarded: Arc<HashSet<TypeId>>,
+    pending: Arc<Mutex<HashMap<(TypeId, String), usize>>>,
+}
+
+impl EchoFilter {
+    fn new(forwarded: HashSet<TypeId>) -> Self {
+        Self {
+            forwarded: Arc::new(forwarded),
+            pending: Default::default(),
+        }
+    }
+
+    fn record<M: 'static>(&self, payload: String) {
+        let type_id = TypeId::of::<M>();
+        if self.forwarded.contains(&type_id) {
+            *self
+                .pending
+                .lock()
+                .unwrap()
+                .entry((type_id, payload))
+                .or_insert(0) += 1;
+        }
+    }
+
+    fn take<M: 'static>(&self, payload: &str) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let key = (TypeId::of::<M>(), payload.to_string());
+        match pending.get_mut(&key) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                true
+            }
+            Some(_) => {
+                pending.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Bridges `ChannelRouter` broadcasts between processes with Postgres LISTEN/NOTIFY.
+///
+/// Messages are sent as JSON on the configured Postgres channels. Use `listen` to announce
+/// incoming messages on the local router, `notify` to send local broadcasts out to the other
+/// replicas, or `bridge` for both directions.
+pub struct PgNotifyBridge {
+    router: ChannelRouter,
+    origin: Uuid,
+    listeners: HashMap<String, Announcer>,
+    forwarders: Vec<(TypeId, Forwarder)>,
+}
+
+impl PgNotifyBridge {
+    pub fn new(router: ChannelRouter) -> Self {
+        Self {
+            router,
+            origin: Uuid::new_v4(),
+            listeners: HashMap::new(),
+            forwarders: vec![],
+        }
+    }
+
+    /// Announce messages NOTIFYed on `channel` on the local router.
+    pub fn listen<M>(mut self, channel: &str) -> Self
+    where
+        M: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    {
+        let announcer: Announcer = Box::new(|router, echoes, value| {
+            let message: M = serde_json::from_value(value)?;
+            echoes.record::<M>(serde_json::to_string(&message)?);
+            router.announce::<M>().send(message).ok();
+            Ok(())
+        });
+        self.listeners.insert(channel.to_string(), announcer);
+        self
+    }
+
+    /// NOTIFY `channel` with every message broadcast on the local router.
+    pub fn notify<M>(mut self, channel: &str) -> Self
+    where
+        M: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    {
+        let channel = channel.to_string();
+        let forwarder: Forwarder = Box::new(move |client, router, origin, echoes| {
+            let channel = channel.clone();
+            let mut rx = router.subscribe::<M>();
+            async move {
+                loop {
+                    let message = match rx.recv().await {
+                        Ok(message) => message,
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!("NOTIFY {} skipped {} messages", channel, skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    let serialized = match serde_json::to_string(&message) {
+                        Ok(serialized) => serialized,
+                        Err(err) => {
+                            tracing::error!("NOTIFY {} serialize failed: {}", channel, err);
+                            continue;
+                        }
+                    };
+                    if echoes.take::<M>(&serialized) {
+                        continue;
+                    }
+                    let envelope = Envelope { origin, message };
+                    let payload = serde_json::to_string(&envelope).expect("serialized above");
+                    if payload.len() > MAX_PAYLOAD_BYTES {
+                        tracing::error!(
+                            "NOTIFY {} payload of {} bytes is too large",
+                            channel,
+                            payload.len()
+                        );
+                        continue;
+                    }
+                    if let Err(err) = client
+                        .execute("SELECT pg_notify($1, $2)", &[&channel, &payload])
+                        .await
+                    {
+                        tracing::error!("NOTIFY {} failed: {}", channel, err);
+                    }
+                }
+            }
+            .boxed()
+        });
+        self.forwarders.push((TypeId::of::<M>(), forwarder));
+        self
+    }
+
+    /// Both `listen` and `notify` on `channel`.
+    pub fn bridge<M>(self, channel: &str) -> Self
+    where
+        M: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    {
+        self.listen::<M>(channel).notify::<M>(channel)
+    }
+
+    /// Run the bridge on a dedicated connection, reconnecting if it is lost. Messages broadcast
+    /// while the connection is down are not delivered.
+    pub fn spawn(self, db_url: String, secure: bool) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = self.run(&db_url, secure).await {
+                    tracing::error!("Postgres notify bridge error: {}", err);
+                }
+                sleep(RECONNECT_DELAY).await;
+            }
+        })
+    }
+
+    async fn run(&self, db_url: &str, secure: bool) -> Result<(), tokio_postgres::Error> {
+        let (client, mut messages) = connect(db_url, secure).await?;
+        let (notification_tx, mut notification_rx) = mpsc::unbounded_channel();
+        let driver = tokio::spawn(async move {
+            while let Some(message) = messages.next().await {
+                match message? {
+                    // The connection closes once the client is dropped, ending this loop.
+                    AsyncMessage::Notification(notification) => {
+                        notification_tx.send(notification).ok();
+                    }
+                    AsyncMessage::Notice(notice) => tracing::debug!("Postgres notice: {}", notice),
+                    _ => {}
+                }
+            }
+            Ok::<_, tokio_postgres::Error>(())
+        });
+
+        let client = Arc::new(client);
+        for channel in self.listeners.keys() {
+            client
+                .batch_execute(&format!("LISTEN \"{}\"", channel.replace('"', "\"\"")))
+                .await?;
+        }
+
+        let echoes = EchoFilter::new(self.forwarders.iter().map(|(id, _)| *id).collect());
+        let forwarders: Vec<_> = self
+            .forwarders
+            .iter()
+            .map(|(_, forwarder)| {
+                tokio::spawn(forwarder(
+                    client.clone(),
+                    self.router.clone(),
+                    self.origin,
+                    echoes.clone(),
+                ))
+            })
+            .collect();
+
+        while let Some(notification) = notification_rx.recv().await {
+            self.dispatch(notification, &echoes);
+        }
+
+        for forwarder in forwarders {
+            forwarder.abort();
+        }
+        driver.await.unwrap_or(Ok(()))
+    }
+
+    fn dispatch(&self, notification: Notification, echoes: &EchoFilter) {
+        let announcer = match self.listeners.get(notification.channel()) {
+            Some(announcer) => announcer,
+            None => return,
+        };
+        let envelope: Envelope<serde_json::Value> =
+            match serde_json::from_str(notification.payload()) {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    tracing::warn!("Invalid payload on {}: {}", notification.channel(), err);
+                    return;
+                }
+            };
+        if envelope.origin == self.origin {
+            return;
+        }
+        if let Err(err) = announcer(&self.router, echoes, envelope.message) {
+            tracing::warn!("Invalid message on {}: {}", notification.channel(), err);
+        }
+    }
+}
+
+async fn connect(
+    db_url: &str,
+    secure: bool,
+) -> Result<(Client, MessageStream), tokio_postgres::Error> {
+    if secure {
+        let rustls_config = rustls::ClientConfig::builder()
+            .with_root_certificates(root_certs())
+            .with_no_client_auth();
+        let tls = tokio_postgres_rustls::MakeRustlsConnect::new(rustls_config);
+        let (client, mut conn) = tokio_postgres::connect(db_url, tls).await?;
+        Ok((
+            client,
+            stream::poll_fn(move |cx| conn.poll_message(cx)).boxed(),
+        ))
+    } else {
+        let (client, mut conn) = tokio_postgres::connect(db_url, NoTls).await?;
+        Ok((
+            client,
+            stream::poll_fn(move |cx| conn.poll_message(cx)).boxed(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use function_name::named;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Ping(u32);
+
+    #[tokio::test]
+    #[named]
+    async fn test_notify_bridge() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let url = harness.db_conf.db_url(&harness.db_name);
+
+        let router_a = ChannelRouter::new();
+        let router_b = ChannelRouter::new();
+        PgNotifyBridge::new(router_a.clone())
+            .bridge::<Ping>("pings")
+            .spawn(url.clone(), false);
+        PgNotifyBridge::new(router_b.clone())
+            .bridge::<Ping>("pings")
+            .spawn(url, false);
+        sleep(Duration::from_millis(500)).await;
+
+        let mut rx_a = router_a.subscribe::<Ping>();
+        let mut rx_b = router_b.subscribe::<Ping>();
+        router_a.announce().send(Ping(1)).expect("send");
+
+        let ping = timeout(Duration::from_secs(5), rx_b.recv())
+            .await
+            .expect("bridged")
+            .expect("recv");
+        assert_eq!(ping, Ping(1));
+        assert_eq!(rx_a.recv().await.expect("recv"), Ping(1));
+
+        // Neither side should see the message bounce back.
+        assert!(timeout(Duration::from_secs(1), rx_a.recv()).await.is_err());
+        assert!(timeout(Duration::from_secs(1), rx_b.recv()).await.is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
 
 use tokio::sync::mpsc;
 use tokio::time::{sleep_until, Duration, Instant};
@@ -159,6 +161,134 @@ pub fn rate_limited_channel<T: Send + Sync>(
     (limited_tx, limited_rx)
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct KeyedRateLimit {
+    /// The minimum time between two attempts for the same key.
+    pub cooldown: Duration,
+    /// The most attempts allowed for the same key within a window.
+    pub max_rate: RateLimit,
+}
+
+/// Limits how often an action can be taken per key, such as per user or per address.
+///
+/// Unlike `rate_limited_channel` this never waits; callers are told how long until the key is
+/// allowed again so they can reject the attempt.
+pub struct KeyedRateLimiter<K> {
+    limit: KeyedRateLimit,
+    attempts: Mutex<HashMap<K, VecDeque<Instant>>>,
+}
+
+impl<K> KeyedRateLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(limit: KeyedRateLimit) -> Self {
+        Self {
+            limit,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long until `key` is allowed another attempt, or `None` if it is allowed now.
+    pub fn retry_after(&self, key: &K) -> Option<Duration> {
+        self.retry_after_at(key, Instant::now())
+    }
+
+    /// Record an attempt for `key`.
+    pub fn record(&self, key: K) {
+        self.record_at(key, Instant::now())
+    }
+
+    /// Record an attempt for `key` if it is allowed, otherwise return how long until it is.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        if let Some(wait) = self.retry_after_in(&attempts, &key, now) {
+            return Err(wait);
+        }
+        self.record_in(&mut attempts, key, now);
+        Ok(())
+    }
+
+    /// Like `check`, but records an attempt for `key` and for `other_key` in `other` only if
+    /// both limiters allow it, returning the longer wait otherwise. Both are locked for the
+    /// whole check so concurrent callers can't pass on the same remaining attempt.
+    pub fn check_with<L>(
+        &self,
+        key: K,
+        other: &KeyedRateLimiter<L>,
+        other_key: L,
+    ) -> Result<(), Duration>
+    where
+        L: Eq + Hash + Clone,
+    {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        let mut other_attempts = other.attempts.lock().unwrap();
+        let wait = self
+            .retry_after_in(&attempts, &key, now)
+            .into_iter()
+            .chain(other.retry_after_in(&other_attempts, &other_key, now))
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+        self.record_in(&mut attempts, key, now);
+        other.record_in(&mut other_attempts, other_key, now);
+        Ok(())
+    }
+
+    fn retry_after_at(&self, key: &K, now: Instant) -> Option<Duration> {
+        self.retry_after_in(&self.attempts.lock().unwrap(), key, now)
+    }
+
+    fn retry_after_in(
+        &self,
+        attempts: &HashMap<K, VecDeque<Instant>>,
+        key: &K,
+        now: Instant,
+    ) -> Option<Duration> {
+        let history = attempts.get(key)?;
+        let window_start = now.checked_sub(self.limit.max_rate.window);
+        let recent: Vec<&Instant> = history
+            .iter()
+            .filter(|at| window_start.is_none_or(|start| **at > start))
+            .collect();
+
+        let cooldown_wait = recent
+            .last()
+            .map(|last| (**last + self.limit.cooldown).saturating_duration_since(now))
+            .filter(|wait| !wait.is_zero());
+        let rate_wait = if recent.len() >= self.limit.max_rate.rate_per_window {
+            recent
+                .first()
+                .map(|first| (**first + self.limit.max_rate.window).saturating_duration_since(now))
+        } else {
+            None
+        };
+        cooldown_wait.into_iter().chain(rate_wait).max()
+    }
+
+    fn record_at(&self, key: K, now: Instant) {
+        self.record_in(&mut self.attempts.lock().unwrap(), key, now)
+    }
+
+    fn record_in(&self, attempts: &mut HashMap<K, VecDeque<Instant>>, key: K, now: Instant) {
+        let window = self.limit.max_rate.window;
+        // Drop keys with no attempts left in the window so the map doesn't grow forever.
+        attempts.retain(|_, history| {
+            while history
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= window)
+            {
+                history.pop_front();
+            }
+            !history.is_empty()
+        });
+        attempts.entry(key).or_default().push_back(now);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -244,4 +374,59 @@ mod test {
         let stop = Instant::now();
         assert!(stop.duration_since(start) > Duration::from_secs(1));
     }
+
+    #[test]
+    fn test_keyed_rate_limiter() {
+        let limiter = KeyedRateLimiter::new(KeyedRateLimit {
+            cooldown: Duration::from_secs(60),
+            max_rate: RateLimit {
+                rate_per_window: 2,
+                window: Duration::from_secs(3600),
+            },
+        });
+        let start = Instant::now();
+        assert_eq!(limiter.retry_after_at(&"a", start), None);
+        limiter.record_at("a", start);
+
+        // The cooldown applies only to the same key
+        assert_eq!(
+            limiter.retry_after_at(&"a", start + Duration::from_secs(10)),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(limiter.retry_after_at(&"b", start), None);
+
+        // After the cooldown the window limit takes over
+        let second = start + Duration::from_secs(60);
+        assert_eq!(limiter.retry_after_at(&"a", second), None);
+        limiter.record_at("a", second);
+        assert_eq!(
+            limiter.retry_after_at(&"a", second + Duration::from_secs(60)),
+            Some(Duration::from_secs(3600 - 120))
+        );
+        assert_eq!(
+            limiter.retry_after_at(&"a", start + Duration::from_secs(3600)),
+            None
+        );
+
+        // A combined check records nothing unless both limiters allow it, and concurrent
+        // checks can't both take the last attempt.
+        let per_user = std::sync::Arc::new(KeyedRateLimiter::new(limiter.limit));
+        let per_address = std::sync::Arc::new(KeyedRateLimiter::new(limiter.limit));
+        let passed = (0..8)
+            .map(|user| {
+                let per_user = per_user.clone();
+                let per_address = per_address.clone();
+                std::thread::spawn(move || per_user.check_with(user, &per_address, "a").is_ok())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|check| check.join().unwrap())
+            .filter(|passed| *passed)
+            .count();
+        assert_eq!(passed, 1);
+        let rejected = (0..8)
+            .find(|user| per_user.retry_after(user).is_none())
+            .expect("a rejected user");
+        assert!(per_user.check_with(rejected, &per_address, "b").is_ok());
+    }
 }
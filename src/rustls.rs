@@ -5,7 +5,7 @@ use std::io::Read;
 use std::path::PathBuf;
 use std::sync::Once;
 
-use reqwest::Certificate;
+use reqwest::{Certificate, Client, ClientBuilder};
 use rustls::pki_types::CertificateDer;
 
 static INIT: Once = Once::new();
@@ -56,3 +56,18 @@ pub fn init_cert_pool<P: Into<PathBuf>>(ca_path: Option<P>) {
 pub fn get_cert_pool() -> Option<&'static CertPool> {
     unsafe { CERT_POOL.as_ref() }
 }
+
+/// A reqwest client builder which trusts the built in roots and the global cert pool.
+pub(crate) fn http_client_builder() -> ClientBuilder {
+    let mut builder = Client::builder()
+        .use_rustls_tls()
+        .tcp_nodelay(true)
+        .tls_built_in_root_certs(true);
+
+    if let Some(cert_pool) = get_cert_pool() {
+        for cert in cert_pool.certs().iter() {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+    }
+    builder
+}
@@ -2,7 +2,39 @@
 
 pub mod auth {
     diesel::table! {
-        auth.metadata (user_id) {
+        email_outbox (id) {
+            id -> Uuid,
+            #[max_length = 255]
+            recipient -> Varchar,
+            template -> Jsonb,
+            #[max_length = 10]
+            status -> Varchar,
+            attempts -> Int4,
+            last_error -> Nullable<Text>,
+            created -> Timestamp,
+            updated -> Timestamp,
+            headers -> Jsonb,
+            transactional -> Bool,
+            send_at -> Nullable<Timestamp>,
+            #[max_length = 255]
+            cancel_key -> Nullable<Varchar>,
+            digest -> Bool,
+        }
+    }
+
+    diesel::table! {
+        email_suppressions (email) {
+            #[max_length = 255]
+            email -> Varchar,
+            #[max_length = 16]
+            reason -> Varchar,
+            detail -> Nullable<Text>,
+            created -> Timestamp,
+        }
+    }
+
+    diesel::table! {
+        metadata (user_id) {
             user_id -> Uuid,
             #[sql_name = "metadata"]
             data -> Jsonb,
@@ -10,23 +42,30 @@ pub mod auth {
     }
 
     diesel::table! {
-        auth.pending_email_verifications (email) {
+        one_time_tokens (id) {
             id -> Varchar,
             email -> Varchar,
             created -> Timestamp,
-            expires -> Timestamp
+            expires -> Timestamp,
+            #[max_length = 128]
+            code_hash -> Nullable<Varchar>,
+            failed_attempts -> Int4,
+            #[max_length = 16]
+            purpose -> Varchar,
+            user_id -> Nullable<Uuid>,
+            payload -> Nullable<Jsonb>,
         }
     }
 
     diesel::table! {
-        auth.portraits (user_id) {
+        portraits (user_id) {
             user_id -> Uuid,
             portrait -> Bytea,
         }
     }
 
     diesel::table! {
-        auth.user_id_accounts (user_id) {
+        user_id_accounts (user_id) {
             user_id -> Uuid,
             username -> Varchar,
             #[max_length = 10]
@@ -35,7 +74,7 @@ pub mod auth {
     }
 
     diesel::table! {
-        auth.users (id) {
+        users (id) {
             id -> Uuid,
             email -> Varchar,
             created -> Timestamp,
@@ -46,5 +85,13 @@ pub mod auth {
     diesel::joinable!(portraits -> users (user_id));
     diesel::joinable!(user_id_accounts -> users (user_id));
 
-    diesel::allow_tables_to_appear_in_same_query!(metadata, portraits, user_id_accounts, users,);
+    diesel::allow_tables_to_appear_in_same_query!(
+        email_outbox,
+        email_suppressions,
+        metadata,
+        one_time_tokens,
+        portraits,
+        user_id_accounts,
+        users,
+    );
 }
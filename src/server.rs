@@ -1,9 +1,18 @@
 use std::env;
 use std::path::PathBuf;
+use std::sync::Arc;
 
+use email_address::EmailAddress;
 use serde::Deserialize;
+use tokio::sync::broadcast;
+use tokio::time::Duration;
 use url::Url;
 
+use crate::email::dkim::{DkimConfig, DkimSigner};
+use crate::email::transport::{AnyTransport, TransportConfig};
+use crate::email::{EmailScheduler, EmailTemplate, ScheduledEmail};
+use crate::rate_limit::RateLimitProfile;
+
 pub trait EnvFilledConfig: Sized {
     fn fill_from_env(self) -> Result<Self, env::VarError>;
 }
@@ -140,6 +149,61 @@ impl DatabaseConfig {
 #[derive(Deserialize, Clone)]
 pub struct EmailConfig {
     pub templates_dir: PathBuf,
+    pub transport: Option<TransportConfig>,
+    /// Reload templates as they are edited, checking every `TEMPLATE_WATCH_PERIOD`. Honoured by
+    /// `EmailConfig::scheduler`.
+    #[serde(default)]
+    pub watch_templates: bool,
+    pub dkim: Option<DkimConfig>,
+}
+
+/// How often `EmailConfig::scheduler` checks the templates for edits when `watch_templates` is
+/// set.
+pub const TEMPLATE_WATCH_PERIOD: Duration = Duration::from_secs(1);
+
+impl EmailConfig {
+    /// An `EmailScheduler` rendering `templates_dir`, which reloads the templates as they are
+    /// edited if `watch_templates` is set.
+    pub fn scheduler<T: EmailTemplate + 'static>(
+        &self,
+        from: EmailAddress,
+        schedule_rx: broadcast::Receiver<ScheduledEmail<T>>,
+        profile: RateLimitProfile,
+    ) -> EmailScheduler<T> {
+        let scheduler = EmailScheduler::new(from, self.templates_dir.clone(), schedule_rx, profile);
+        if self.watch_templates {
+            scheduler.watch_templates(TEMPLATE_WATCH_PERIOD)
+        } else {
+            scheduler
+        }
+    }
+
+    /// The configured transport, DKIM signing if `dkim` is set. Fails if `dkim` is set for a
+    /// transport other than SMTP.
+    pub fn build_transport(&self) -> anyhow::Result<Option<AnyTransport>> {
+        let Some(transport) = self.transport.clone() else {
+            return Ok(None);
+        };
+        let transport = transport.build()?;
+        Ok(Some(match &self.dkim {
+            Some(dkim) => transport.dkim(Arc::new(DkimSigner::new(dkim)?))?,
+            None => transport,
+        }))
+    }
+}
+
+impl EnvFilledConfig for EmailConfig {
+    fn fill_from_env(self) -> Result<Self, env::VarError> {
+        Ok(Self {
+            templates_dir: self.templates_dir,
+            transport: match self.transport {
+                Some(transport) => Some(transport.fill_from_env()?),
+                None => None,
+            },
+            watch_templates: self.watch_templates,
+            dkim: self.dkim,
+        })
+    }
 }
 
 #[derive(Deserialize)]
@@ -167,7 +231,10 @@ impl TryFrom<BaseConfig> for InnerConfig {
     fn try_from(conf: BaseConfig) -> Result<Self, Self::Error> {
         Ok(Self {
             database: conf.database.fill_from_env()?,
-            email: conf.email,
+            email: match conf.email {
+                Some(email) => Some(email.fill_from_env()?),
+                None => None,
+            },
             frontend: conf.frontend,
             oidc: match conf.oidc {
                 Some(oidc) => Some(oidc.fill_from_env()?),
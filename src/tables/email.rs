@@ -1,9 +1,17 @@
+use std::future::Future;
+use std::sync::Arc;
+
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel_async::AsyncPgConnection;
 use email_address::EmailAddress;
 use rand::{thread_rng, Rng};
 use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use super::{DbPool, TokenPurpose};
 
 pub fn gen_rand_string(num_bytes: usize) -> String {
     let random_bytes: Vec<u8> = (0..num_bytes).map(|_| thread_rng().gen::<u8>()).collect();
@@ -11,11 +19,58 @@ pub fn gen_rand_string(num_bytes: usize) -> String {
     base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
 }
 
+/// Tokens are only stored as their SHA-256 hash so reading the database doesn't allow anyone
+/// to complete a verification.
+pub fn hash_token(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Verification codes are between 6 and 8 digits.
+pub const MIN_CODE_DIGITS: u32 = 6;
+pub const MAX_CODE_DIGITS: u32 = 8;
+/// Wrong guesses allowed before a verification code is deleted.
+pub const MAX_CODE_ATTEMPTS: i32 = 5;
+
+/// A zero-padded random numeric code, with `digits` clamped to the supported range.
+pub fn gen_numeric_code(digits: u32) -> String {
+    let digits = digits.clamp(MIN_CODE_DIGITS, MAX_CODE_DIGITS);
+    let code = thread_rng().gen_range(0..10u32.pow(digits));
+    format!("{:0width$}", code, width = digits as usize)
+}
+
+/// Codes are short enough to guess offline from a bare hash, so each is salted with the id of
+/// its row. The attempt limit is what protects codes online.
+pub fn hash_code(id: &str, code: &str) -> String {
+    hash_token(&format!("{}:{}", id, code))
+}
+
 pub enum EmailVerification {
     Accepted(EmailAddress),
     Denied,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct PurgeProfile {
+    /// How often expired verifications are purged.
+    pub interval: Duration,
+    /// The most rows deleted per statement, to keep the locks held short.
+    pub batch_size: i64,
+}
+
+impl Default for PurgeProfile {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60 * 60),
+            batch_size: 1000,
+        }
+    }
+}
+
 pub trait UnverifiedEmailTable: Sized + Clone + Send {
     fn create(
         conn: &mut AsyncPgConnection,
@@ -34,6 +89,89 @@ pub trait UnverifiedEmailTable: Sized + Clone + Send {
         self,
         conn: &mut AsyncPgConnection,
     ) -> impl std::future::Future<Output = QueryResult<EmailVerification>> + Send;
+    /// Create a single-use token for `purpose`, returning the raw token. Only its hash is stored.
+    fn create_token(
+        conn: &mut AsyncPgConnection,
+        email: &EmailAddress,
+        purpose: TokenPurpose,
+    ) -> impl std::future::Future<Output = QueryResult<String>> + Send;
+    /// Consume a token created for `purpose`. Tokens for other purposes are never accepted.
+    fn consume_token(
+        conn: &mut AsyncPgConnection,
+        token: &str,
+        purpose: TokenPurpose,
+    ) -> impl std::future::Future<Output = QueryResult<EmailVerification>> + Send;
+    /// Create a numeric verification code for clients which can't follow links, returning the
+    /// code to send.
+    fn create_code(
+        conn: &mut AsyncPgConnection,
+        email: &EmailAddress,
+        digits: u32,
+    ) -> impl std::future::Future<Output = QueryResult<String>> + Send;
+    /// Check `code` against the newest code issued for `email`. The code is consumed when it
+    /// matches. After `MAX_CODE_ATTEMPTS` wrong guesses every code issued for `email` is deleted.
+    fn verify_code(
+        conn: &mut AsyncPgConnection,
+        email: &EmailAddress,
+        code: &str,
+    ) -> impl std::future::Future<Output = QueryResult<EmailVerification>> + Send;
+    /// Delete all but the newest `keep` tokens issued to `email` for `purpose`, so issuing a new
+    /// token invalidates the older ones. Tokens for other purposes are left alone.
+    fn invalidate_older(
+        conn: &mut AsyncPgConnection,
+        email: &EmailAddress,
+        purpose: TokenPurpose,
+        keep: i64,
+    ) -> impl std::future::Future<Output = QueryResult<usize>> + Send;
+    /// Delete up to `batch_size` expired tokens, returning how many were deleted.
+    fn purge_expired(
+        conn: &mut AsyncPgConnection,
+        batch_size: i64,
+    ) -> impl std::future::Future<Output = QueryResult<usize>> + Send;
+
+    /// Periodically purge expired verifications until `shutdown` resolves.
+    fn spawn_purge_task<F>(pool: Arc<DbPool>, profile: PurgeProfile, shutdown: F) -> JoinHandle<()>
+    where
+        Self: 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(async move {
+            tokio::pin!(shutdown);
+            let mut interval = tokio::time::interval(profile.interval);
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = interval.tick() => {}
+                }
+                let mut conn = match pool.get().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::error!("Verification purge could not get a connection: {}", err);
+                        continue;
+                    }
+                };
+                let mut purged = 0;
+                loop {
+                    match Self::purge_expired(&mut conn, profile.batch_size).await {
+                        Ok(count) => {
+                            purged += count;
+                            if (count as i64) < profile.batch_size {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            tracing::error!("Verification purge failed: {}", err);
+                            break;
+                        }
+                    }
+                }
+                if purged > 0 {
+                    tracing::info!("Purged {} expired email verifications", purged);
+                }
+            }
+            tracing::info!("Verification purge shutting down");
+        })
+    }
 }
 
 #[allow(clippy::crate_in_macro_def)]
@@ -43,13 +181,25 @@ macro_rules! create_async_email_table {
         use diesel_async::{AsyncPgConnection, RunQueryDsl};
         const MINUTES_VERIFICATION_VALID: chrono::Duration = chrono::Duration::minutes($minutes);
 
+        /// `$minutes` unless a lifetime was set at runtime with `set_token_ttl`.
+        fn verification_ttl() -> chrono::Duration {
+            $crate::tables::TokenPurpose::Verify
+                .configured_ttl()
+                .unwrap_or(MINUTES_VERIFICATION_VALID)
+        }
+
         #[derive(PartialEq, Queryable, Insertable, Clone, Debug)]
-        #[diesel(table_name = crate::schema::auth::pending_email_verifications)]
+        #[diesel(table_name = crate::schema::auth::one_time_tokens)]
         pub struct PendingEmailVerification {
             id: String,
             email: String,
             created: NaiveDateTime,
             expires: NaiveDateTime,
+            code_hash: Option<String>,
+            failed_attempts: i32,
+            purpose: String,
+            user_id: Option<$crate::tables::UserId>,
+            payload: Option<serde_json::Value>,
         }
 
         impl UnverifiedEmailTable for PendingEmailVerification {
@@ -58,32 +208,144 @@ macro_rules! create_async_email_table {
                 email: &EmailAddress,
                 base_url: &str,
             ) -> QueryResult<String> {
-                use crate::schema::auth::pending_email_verifications::dsl as pending;
+                let token =
+                    Self::create_token(conn, email, $crate::tables::TokenPurpose::Verify).await?;
+                Ok(format!($link_uri_fmt, base_url, token))
+            }
+
+            async fn create_token(
+                conn: &mut AsyncPgConnection,
+                email: &EmailAddress,
+                purpose: $crate::tables::TokenPurpose,
+            ) -> QueryResult<String> {
+                let ttl = match purpose {
+                    $crate::tables::TokenPurpose::Verify => verification_ttl(),
+                    _ => purpose.ttl(),
+                };
+                $crate::tables::OneTimeToken::builder(purpose, email)
+                    .ttl(ttl)
+                    .issue(conn)
+                    .await
+            }
+
+            async fn consume_token(
+                conn: &mut AsyncPgConnection,
+                token: &str,
+                purpose: $crate::tables::TokenPurpose,
+            ) -> QueryResult<EmailVerification> {
+                Ok(
+                    match $crate::tables::OneTimeToken::consume(conn, purpose, token).await? {
+                        Some(row) => EmailVerification::Accepted(
+                            EmailAddress::from_str(&row.email).expect("valid email"),
+                        ),
+                        None => EmailVerification::Denied,
+                    },
+                )
+            }
+
+            async fn create_code(
+                conn: &mut AsyncPgConnection,
+                email: &EmailAddress,
+                digits: u32,
+            ) -> QueryResult<String> {
+                use crate::schema::auth::one_time_tokens::dsl as pending;
 
                 let now = chrono::Utc::now().naive_utc();
+                // The id is never handed out, so the row can only be reached through its code.
+                let id = $crate::tables::hash_token(&gen_rand_string(32));
+                let code = $crate::tables::gen_numeric_code(digits);
                 let row = Self {
-                    id: gen_rand_string(32),
+                    code_hash: Some($crate::tables::hash_code(&id, &code)),
+                    id,
                     email: email.to_string(),
                     created: now,
-                    expires: now + MINUTES_VERIFICATION_VALID,
+                    expires: now + verification_ttl(),
+                    failed_attempts: 0,
+                    purpose: $crate::tables::TokenPurpose::Verify.to_string(),
+                    user_id: None,
+                    payload: None,
                 };
-                diesel::insert_into(pending::pending_email_verifications)
+                diesel::insert_into(pending::one_time_tokens)
                     .values(&row)
                     .execute(conn)
                     .await?;
-                Ok(format!($link_uri_fmt, base_url, row.id))
+                Ok(code)
+            }
+
+            async fn verify_code(
+                conn: &mut AsyncPgConnection,
+                email: &EmailAddress,
+                code: &str,
+            ) -> QueryResult<EmailVerification> {
+                use crate::schema::auth::one_time_tokens::dsl as pending;
+
+                let row = pending::one_time_tokens
+                    .filter(pending::email.eq(email.to_string()))
+                    .filter(pending::purpose.eq($crate::tables::TokenPurpose::Verify.as_str()))
+                    .filter(pending::code_hash.is_not_null())
+                    .order(pending::created.desc())
+                    .first::<PendingEmailVerification>(conn)
+                    .await
+                    .optional()?;
+                let row = match row {
+                    Some(row) => row,
+                    None => return Ok(EmailVerification::Denied),
+                };
+                // Reserve the attempt before comparing so concurrent guesses each use one up.
+                let attempts: Option<i32> = diesel::update(
+                    pending::one_time_tokens
+                        .filter(pending::id.eq(&row.id))
+                        .filter(pending::failed_attempts.lt($crate::tables::MAX_CODE_ATTEMPTS)),
+                )
+                .set(pending::failed_attempts.eq(pending::failed_attempts + 1))
+                .returning(pending::failed_attempts)
+                .get_result(conn)
+                .await
+                .optional()?;
+                let matches = attempts.is_some()
+                    && row.code_hash.as_deref().is_some_and(|hash| {
+                        $crate::tables::constant_time_eq(
+                            hash,
+                            &$crate::tables::hash_code(&row.id, code),
+                        )
+                    });
+                if matches {
+                    return row.inspect_pending_verification(conn).await;
+                }
+
+                let failed = attempts.unwrap_or($crate::tables::MAX_CODE_ATTEMPTS);
+                if failed >= $crate::tables::MAX_CODE_ATTEMPTS {
+                    // Drop every outstanding code, otherwise guessing moves on to the older ones.
+                    diesel::delete(
+                        pending::one_time_tokens
+                            .filter(pending::email.eq(&row.email))
+                            .filter(
+                                pending::purpose.eq($crate::tables::TokenPurpose::Verify.as_str()),
+                            )
+                            .filter(pending::code_hash.is_not_null()),
+                    )
+                    .execute(conn)
+                    .await?;
+                }
+                Ok(EmailVerification::Denied)
             }
 
             async fn get_pending_verification(
                 conn: &mut AsyncPgConnection,
                 verifier: &str,
             ) -> QueryResult<Self> {
-                use crate::schema::auth::pending_email_verifications::dsl as pending;
+                use crate::schema::auth::one_time_tokens::dsl as pending;
 
-                pending::pending_email_verifications
-                    .filter(pending::id.eq(&verifier))
+                let hashed = $crate::tables::hash_token(verifier);
+                let row = pending::one_time_tokens
+                    .filter(pending::id.eq(&hashed))
+                    .filter(pending::purpose.eq($crate::tables::TokenPurpose::Verify.as_str()))
                     .first::<PendingEmailVerification>(conn)
-                    .await
+                    .await?;
+                if !$crate::tables::constant_time_eq(&row.id, &hashed) {
+                    return Err(diesel::result::Error::NotFound);
+                }
+                Ok(row)
             }
 
             fn expires(&self) -> NaiveDateTime {
@@ -94,26 +356,56 @@ macro_rules! create_async_email_table {
                 self,
                 conn: &mut AsyncPgConnection,
             ) -> QueryResult<EmailVerification> {
-                use crate::schema::auth::pending_email_verifications::dsl as pending;
+                use crate::schema::auth::one_time_tokens::dsl as pending;
 
                 Ok(if self.is_valid() {
-                    diesel::delete(
-                        pending::pending_email_verifications.filter(pending::id.eq(self.id)),
-                    )
-                    .execute(conn)
-                    .await?;
+                    diesel::delete(pending::one_time_tokens.filter(pending::id.eq(self.id)))
+                        .execute(conn)
+                        .await?;
                     EmailVerification::Accepted(
                         EmailAddress::from_str(&self.email).expect("valid email"),
                     )
                 } else {
-                    diesel::delete(
-                        pending::pending_email_verifications.filter(pending::id.eq(self.id)),
-                    )
-                    .execute(conn)
-                    .await?;
+                    diesel::delete(pending::one_time_tokens.filter(pending::id.eq(self.id)))
+                        .execute(conn)
+                        .await?;
                     EmailVerification::Denied
                 })
             }
+
+            async fn invalidate_older(
+                conn: &mut AsyncPgConnection,
+                email: &EmailAddress,
+                purpose: $crate::tables::TokenPurpose,
+                keep: i64,
+            ) -> QueryResult<usize> {
+                use crate::schema::auth::one_time_tokens::dsl as pending;
+
+                let email = email.to_string();
+                let newest: Vec<String> = pending::one_time_tokens
+                    .select(pending::id)
+                    .filter(pending::email.eq(&email))
+                    .filter(pending::purpose.eq(purpose.as_str()))
+                    .order(pending::created.desc())
+                    .limit(keep)
+                    .load(conn)
+                    .await?;
+                diesel::delete(
+                    pending::one_time_tokens
+                        .filter(pending::email.eq(&email))
+                        .filter(pending::purpose.eq(purpose.as_str()))
+                        .filter(pending::id.ne_all(newest)),
+                )
+                .execute(conn)
+                .await
+            }
+
+            async fn purge_expired(
+                conn: &mut AsyncPgConnection,
+                batch_size: i64,
+            ) -> QueryResult<usize> {
+                $crate::tables::OneTimeToken::purge_expired(conn, batch_size).await
+            }
         }
     };
 }
@@ -177,4 +469,176 @@ mod test {
             EmailVerification::Denied => panic!("verification should not be denied"),
         }
     }
+
+    #[tokio::test]
+    #[named]
+    async fn test_purge_expired_verifications() {
+        use crate::schema::auth::one_time_tokens::dsl as pending;
+
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+
+        let email = EmailAddress::from_str("test@example.com").expect("valid email");
+        PendingEmailVerification::create(&mut conn, &email, "https://localhost/")
+            .await
+            .expect("created pending");
+        let created = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(10);
+        let expired = PendingEmailVerification {
+            id: gen_rand_string(32),
+            email: email.to_string(),
+            created,
+            expires: created + chrono::Duration::minutes(1),
+            code_hash: None,
+            failed_attempts: 0,
+            purpose: TokenPurpose::Verify.to_string(),
+            user_id: None,
+            payload: None,
+        };
+        diesel::insert_into(pending::one_time_tokens)
+            .values(&expired)
+            .execute(&mut conn)
+            .await
+            .expect("inserted expired");
+
+        let purged = PendingEmailVerification::purge_expired(&mut conn, 10)
+            .await
+            .expect("purged");
+        assert_eq!(purged, 1);
+        let purged = PendingEmailVerification::purge_expired(&mut conn, 10)
+            .await
+            .expect("purged");
+        assert_eq!(purged, 0);
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_verification_code_attempts() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+
+        let email = EmailAddress::from_str("test@example.com").expect("valid email");
+        let code = PendingEmailVerification::create_code(&mut conn, &email, 6)
+            .await
+            .expect("created code");
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        let denied = PendingEmailVerification::verify_code(&mut conn, &email, wrong)
+            .await
+            .expect("checked code");
+        assert!(matches!(denied, EmailVerification::Denied));
+        let accepted = PendingEmailVerification::verify_code(&mut conn, &email, &code)
+            .await
+            .expect("checked code");
+        match accepted {
+            EmailVerification::Accepted(accepted_email) => assert_eq!(accepted_email, email),
+            EmailVerification::Denied => panic!("verification should not be denied"),
+        }
+
+        // Once the attempts are used up even the right code is refused, and so are the codes
+        // sent before it.
+        let older = PendingEmailVerification::create_code(&mut conn, &email, 6)
+            .await
+            .expect("created code");
+        let code = PendingEmailVerification::create_code(&mut conn, &email, 8)
+            .await
+            .expect("created code");
+        let wrong = if code == "00000000" {
+            "00000001"
+        } else {
+            "00000000"
+        };
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            PendingEmailVerification::verify_code(&mut conn, &email, wrong)
+                .await
+                .expect("checked code");
+        }
+        let locked = PendingEmailVerification::verify_code(&mut conn, &email, &code)
+            .await
+            .expect("checked code");
+        assert!(matches!(locked, EmailVerification::Denied));
+        let locked = PendingEmailVerification::verify_code(&mut conn, &email, &older)
+            .await
+            .expect("checked code");
+        assert!(matches!(locked, EmailVerification::Denied));
+
+        // Guesses sent at the same time each use up an attempt.
+        let code = PendingEmailVerification::create_code(&mut conn, &email, 6)
+            .await
+            .expect("created code");
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        let mut conns = vec![];
+        for _ in 0..MAX_CODE_ATTEMPTS * 2 {
+            conns.push(harness.conn().await);
+        }
+        futures_util::future::join_all(
+            conns
+                .iter_mut()
+                .map(|conn| PendingEmailVerification::verify_code(conn, &email, wrong)),
+        )
+        .await;
+        let locked = PendingEmailVerification::verify_code(&mut conn, &email, &code)
+            .await
+            .expect("checked code");
+        assert!(matches!(locked, EmailVerification::Denied));
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_token_purpose() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+
+        let email = EmailAddress::from_str("test@example.com").expect("valid email");
+        let token = PendingEmailVerification::create_token(&mut conn, &email, TokenPurpose::Login)
+            .await
+            .expect("created token");
+
+        // A login token can't be used to verify an email.
+        assert!(
+            PendingEmailVerification::get_pending_verification(&mut conn, &token)
+                .await
+                .is_err()
+        );
+        let denied =
+            PendingEmailVerification::consume_token(&mut conn, &token, TokenPurpose::Verify)
+                .await
+                .expect("checked token");
+        assert!(matches!(denied, EmailVerification::Denied));
+
+        let accepted =
+            PendingEmailVerification::consume_token(&mut conn, &token, TokenPurpose::Login)
+                .await
+                .expect("checked token");
+        assert!(matches!(accepted, EmailVerification::Accepted(_)));
+        let reused =
+            PendingEmailVerification::consume_token(&mut conn, &token, TokenPurpose::Login)
+                .await
+                .expect("checked token");
+        assert!(matches!(reused, EmailVerification::Denied));
+
+        // Resending a verification doesn't invalidate tokens issued for other purposes.
+        let reset = PendingEmailVerification::create_token(&mut conn, &email, TokenPurpose::Reset)
+            .await
+            .expect("created token");
+        for _ in 0..2 {
+            PendingEmailVerification::create_token(&mut conn, &email, TokenPurpose::Verify)
+                .await
+                .expect("created token");
+        }
+        let deleted =
+            PendingEmailVerification::invalidate_older(&mut conn, &email, TokenPurpose::Verify, 1)
+                .await
+                .expect("invalidated");
+        assert_eq!(deleted, 1);
+        let accepted =
+            PendingEmailVerification::consume_token(&mut conn, &reset, TokenPurpose::Reset)
+                .await
+                .expect("checked token");
+        assert!(matches!(accepted, EmailVerification::Accepted(_)));
+    }
 }
@@ -1,19 +1,85 @@
 pub mod email;
+pub mod outbox;
+pub mod suppressions;
+pub mod tokens;
 pub mod users;
 
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
 use diesel::{ConnectionError, ConnectionResult};
 use diesel_async::pooled_connection::{bb8::Pool, AsyncDieselConnectionManager, ManagerConfig};
-use diesel_async::AsyncPgConnection;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 use futures_util::future::{BoxFuture, FutureExt};
 use tokio::time::Duration;
 
 use crate::get_cert_pool;
-pub use crate::tables::email::{gen_rand_string, EmailVerification, UnverifiedEmailTable};
+pub use crate::tables::email::{
+    constant_time_eq, gen_numeric_code, gen_rand_string, hash_code, hash_token, EmailVerification,
+    PurgeProfile, UnverifiedEmailTable, MAX_CODE_ATTEMPTS, MAX_CODE_DIGITS, MIN_CODE_DIGITS,
+};
+pub use crate::tables::outbox::OutboxEmail;
+pub use crate::tables::suppressions::{Suppression, SuppressionReason};
+pub use crate::tables::tokens::{set_token_ttl, IssueToken, OneTimeToken, TokenPurpose};
 pub use crate::tables::users::{UserAccountType, UserId, UserIdTable, UserTable};
 
 pub type DbPool = Pool<AsyncPgConnection>;
 const DB_TIMEOUT: Duration = Duration::from_secs(3);
 
+pub const DEFAULT_AUTH_SCHEMA: &str = "auth";
+static AUTH_SCHEMA: OnceLock<String> = OnceLock::new();
+
+/// Sets the schema the auth tables live in, which allows several apps to share one database or
+/// avoids clashing with an existing `auth` schema. This must be called before migrations are run
+/// and before the connection pool is established; it can only be set once per process.
+pub fn init_auth_schema(schema: &str) -> anyhow::Result<()> {
+    if !is_valid_schema_name(schema) {
+        return Err(anyhow!("Invalid schema name: {}", schema));
+    }
+    AUTH_SCHEMA
+        .set(schema.to_string())
+        .map_err(|_| anyhow!("Auth schema already set to {}", auth_schema()))
+}
+
+pub fn auth_schema() -> &'static str {
+    AUTH_SCHEMA
+        .get()
+        .map(|schema| schema.as_str())
+        .unwrap_or(DEFAULT_AUTH_SCHEMA)
+}
+
+/// Schema names are interpolated into SQL, so only plain lowercase identifiers are accepted.
+fn is_valid_schema_name(schema: &str) -> bool {
+    let mut chars = schema.chars();
+    schema.len() <= 63
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+pub(crate) fn search_path_query() -> String {
+    format!("SET search_path TO \"{}\", public", auth_schema())
+}
+
+/// Points the unqualified tables of `crate::schema` at the configured auth schema.
+pub async fn set_search_path(conn: &mut AsyncPgConnection) -> ConnectionResult<()> {
+    diesel::sql_query(search_path_query())
+        .execute(conn)
+        .await
+        .map_err(ConnectionError::CouldntSetupConfiguration)?;
+    Ok(())
+}
+
+fn establish_connection(config: &str) -> BoxFuture<'_, ConnectionResult<AsyncPgConnection>> {
+    let fut = async move {
+        let mut conn = AsyncPgConnection::establish(config).await?;
+        set_search_path(&mut conn).await?;
+        Ok(conn)
+    };
+    fut.boxed()
+}
+
 fn establish_secure_connection(config: &str) -> BoxFuture<ConnectionResult<AsyncPgConnection>> {
     let fut = async move {
         // We first set up the way we want rustls to work.
@@ -34,12 +100,14 @@ fn establish_secure_connection(config: &str) -> BoxFuture<ConnectionResult<Async
                 }
             }
         });
-        AsyncPgConnection::try_from(client).await
+        let mut conn = AsyncPgConnection::try_from(client).await?;
+        set_search_path(&mut conn).await?;
+        Ok(conn)
     };
     fut.boxed()
 }
 
-fn root_certs() -> rustls::RootCertStore {
+pub(crate) fn root_certs() -> rustls::RootCertStore {
     let mut roots = rustls::RootCertStore::empty();
     let certs = rustls_native_certs::load_native_certs().expect("Certs not loadable!");
     roots.add_parsable_certificates(certs);
@@ -54,6 +122,8 @@ pub async fn establish_connection_pool(db_url: &str, secure: bool) -> anyhow::Re
     let mut config = ManagerConfig::default();
     if secure {
         config.custom_setup = Box::new(establish_secure_connection);
+    } else {
+        config.custom_setup = Box::new(establish_connection);
     }
     let manager =
         AsyncDieselConnectionManager::<AsyncPgConnection>::new_with_config(db_url, config);
@@ -140,15 +210,190 @@ impl diesel::result::DatabaseErrorInformation for ValidationErrorMessage {
 }
 
 pub mod harness {
+    use super::{auth_schema, set_search_path};
     use crate::server::DatabaseConfig;
-    use diesel::migration::MigrationSource;
+    use diesel::connection::BoxableConnection;
+    use diesel::migration::{
+        Migration, MigrationMetadata, MigrationName, MigrationSource, MigrationVersion,
+    };
     use diesel::prelude::*;
     use diesel::{pg::Pg, sql_query};
     use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
     use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
     use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
-    pub const AUTH_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
+    type MigrationResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
+
+    /// The auth migrations, run by [`run_auth_migrations`] so they land in the configured
+    /// schema.
+    const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
+
+    /// The auth migrations. Running them directly creates the tables added since the schema
+    /// became configurable in the connection's search_path rather than next to the `auth` ones.
+    #[deprecated(note = "run the auth migrations with `run_auth_migrations`")]
+    pub const AUTH_MIGRATIONS: EmbeddedMigrations = MIGRATIONS;
+
+    /// The migrations released before the schema was configurable create their tables in
+    /// `auth` explicitly. They are left as they shipped and rewritten for other schemas when run,
+    /// the later migrations use unqualified names and rely on the search_path.
+    const QUALIFIED_MIGRATIONS: &[(&str, &str, &str)] = &[
+        (
+            "20240105051507",
+            include_str!("../../migrations/2024-01-05-051507_auth/up.sql"),
+            include_str!("../../migrations/2024-01-05-051507_auth/down.sql"),
+        ),
+        (
+            "20240117002637",
+            include_str!("../../migrations/2024-01-17-002637_account_type/up.sql"),
+            include_str!("../../migrations/2024-01-17-002637_account_type/down.sql"),
+        ),
+        (
+            "20240122185420",
+            include_str!("../../migrations/2024-01-22-185420_portraits/up.sql"),
+            include_str!("../../migrations/2024-01-22-185420_portraits/down.sql"),
+        ),
+        (
+            "20240704003456",
+            include_str!("../../migrations/2024-07-04-003456_drop_unique_email_constraint/up.sql"),
+            include_str!(
+                "../../migrations/2024-07-04-003456_drop_unique_email_constraint/down.sql"
+            ),
+        ),
+        (
+            "20240704030732",
+            include_str!("../../migrations/2024-07-04-030732_pending_email_verifications/up.sql"),
+            include_str!("../../migrations/2024-07-04-030732_pending_email_verifications/down.sql"),
+        ),
+        (
+            "20240707024852",
+            include_str!("../../migrations/2024-07-07-024852_drop_unique_username/up.sql"),
+            include_str!("../../migrations/2024-07-07-024852_drop_unique_username/down.sql"),
+        ),
+    ];
+
+    /// [`MIGRATIONS`] with the qualified migrations pointed at `schema`. Versions are
+    /// unchanged, so a database keeps the same history whatever schema it uses.
+    struct AuthMigrations {
+        schema: &'static str,
+    }
+
+    impl MigrationSource<Pg> for AuthMigrations {
+        fn migrations(&self) -> MigrationResult<Vec<Box<dyn Migration<Pg>>>> {
+            let migrations = <EmbeddedMigrations as MigrationSource<Pg>>::migrations(&MIGRATIONS)?;
+            if self.schema == super::DEFAULT_AUTH_SCHEMA {
+                return Ok(migrations);
+            }
+            Ok(migrations
+                .into_iter()
+                .map(|migration| {
+                    let version = migration.name().version().to_string();
+                    match QUALIFIED_MIGRATIONS.iter().find(|(v, _, _)| *v == version) {
+                        Some((_, up, down)) => Box::new(RequalifiedMigration {
+                            migration,
+                            up: requalify(up, self.schema),
+                            down: requalify(down, self.schema),
+                        }),
+                        None => migration,
+                    }
+                })
+                .collect())
+        }
+    }
+
+    struct RequalifiedMigration {
+        migration: Box<dyn Migration<Pg>>,
+        up: String,
+        down: String,
+    }
+
+    impl Migration<Pg> for RequalifiedMigration {
+        fn run(&self, conn: &mut dyn BoxableConnection<Pg>) -> MigrationResult<()> {
+            conn.batch_execute(&self.up)?;
+            Ok(())
+        }
+
+        fn revert(&self, conn: &mut dyn BoxableConnection<Pg>) -> MigrationResult<()> {
+            conn.batch_execute(&self.down)?;
+            Ok(())
+        }
+
+        fn metadata(&self) -> &dyn MigrationMetadata {
+            self.migration.metadata()
+        }
+
+        fn name(&self) -> &dyn MigrationName {
+            self.migration.name()
+        }
+    }
+
+    /// Moves the tables of a qualified migration from `auth` to `schema`, which
+    /// [`run_auth_migrations`] has already created.
+    fn requalify(sql: &str, schema: &str) -> String {
+        sql.replace("CREATE SCHEMA IF NOT EXISTS auth;", "")
+            .replace("auth.", &format!("\"{}\".", schema))
+    }
+
+    /// Runs the auth migrations inside the schema set by [`super::init_auth_schema`].
+    ///
+    /// The migrations are tracked in a `__diesel_schema_migrations` table inside that schema so
+    /// each app sharing a database keeps its own history. The connection's search_path is reset
+    /// afterwards so the app's own migrations are unaffected.
+    pub fn run_auth_migrations<C>(
+        connection: &mut C,
+    ) -> MigrationResult<Vec<MigrationVersion<'static>>>
+    where
+        C: MigrationHarness<Pg> + Connection<Backend = Pg>,
+    {
+        run_migrations_in_schema(connection, auth_schema())
+    }
+
+    fn run_migrations_in_schema<C>(
+        connection: &mut C,
+        schema: &'static str,
+    ) -> MigrationResult<Vec<MigrationVersion<'static>>>
+    where
+        C: MigrationHarness<Pg> + Connection<Backend = Pg>,
+    {
+        connection.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS \"{}\"", schema))?;
+        adopt_legacy_migrations(connection, schema)?;
+        connection.batch_execute(&format!("SET search_path TO \"{}\", public", schema))?;
+        let applied = connection
+            .run_pending_migrations(AuthMigrations { schema })
+            .map(|versions| versions.into_iter().map(|v| v.as_owned()).collect());
+        connection.batch_execute("RESET search_path")?;
+        applied
+    }
+
+    /// Before the schema was configurable the auth migrations were tracked in the public
+    /// migrations table. If the auth tables already exist, copy those entries into the schema's
+    /// own table so they aren't applied a second time.
+    fn adopt_legacy_migrations<C>(connection: &mut C, schema: &str) -> MigrationResult<()>
+    where
+        C: Connection<Backend = Pg>,
+    {
+        let versions = <EmbeddedMigrations as MigrationSource<Pg>>::migrations(&MIGRATIONS)?
+            .iter()
+            .map(|mig| format!("'{}'", mig.name().version()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        connection.batch_execute(&format!(
+            r#"DO $$
+            BEGIN
+                IF to_regclass('"{schema}".users') IS NOT NULL
+                   AND to_regclass('"{schema}".__diesel_schema_migrations') IS NULL
+                   AND to_regclass('public.__diesel_schema_migrations') IS NOT NULL THEN
+                    CREATE TABLE "{schema}".__diesel_schema_migrations (
+                        version VARCHAR(50) PRIMARY KEY NOT NULL,
+                        run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+                    );
+                    INSERT INTO "{schema}".__diesel_schema_migrations
+                        SELECT version, run_on FROM public.__diesel_schema_migrations
+                        WHERE version IN ({versions});
+                END IF;
+            END $$;"#
+        ))?;
+        Ok(())
+    }
 
     pub fn to_pg_db_name(name: &str) -> String {
         let mut db_name = String::new();
@@ -186,7 +431,8 @@ pub mod harness {
             #[diesel(sql_type = diesel::sql_types::Text)]
             tablename: String,
         }
-        sql_query("SELECT tablename FROM pg_tables WHERE schemaname = 'auth'")
+        sql_query("SELECT tablename FROM pg_tables WHERE schemaname = $1")
+            .bind::<diesel::sql_types::Text, _>(auth_schema())
             .load::<Table>(connection)
             .await
             .map(|tables| tables.into_iter().map(|t| t.tablename).collect())
@@ -195,30 +441,27 @@ pub mod harness {
     fn run_migrations(
         url: &str,
         server_migrations: Option<EmbeddedMigrations>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    ) -> MigrationResult<()> {
         use std::thread::spawn;
         let url = url.to_string();
-        spawn(
-            move || -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-                let mut connection = AsyncConnectionWrapper::<AsyncPgConnection>::establish(&url)?;
-                for mig in <EmbeddedMigrations as MigrationSource<Pg>>::migrations(&AUTH_MIGRATIONS)
-                    .unwrap()
+        spawn(move || -> MigrationResult<()> {
+            let mut connection = AsyncConnectionWrapper::<AsyncPgConnection>::establish(&url)?;
+            for mig in <EmbeddedMigrations as MigrationSource<Pg>>::migrations(&MIGRATIONS).unwrap()
+            {
+                eprintln!("migration: {}", mig.name());
+            }
+            run_auth_migrations(&mut connection)?;
+            if let Some(server_migrations) = server_migrations {
+                for mig in
+                    <EmbeddedMigrations as MigrationSource<Pg>>::migrations(&server_migrations)
+                        .unwrap()
                 {
                     eprintln!("migration: {}", mig.name());
                 }
-                connection.run_pending_migrations(AUTH_MIGRATIONS)?;
-                if let Some(server_migrations) = server_migrations {
-                    for mig in
-                        <EmbeddedMigrations as MigrationSource<Pg>>::migrations(&server_migrations)
-                            .unwrap()
-                    {
-                        eprintln!("migration: {}", mig.name());
-                    }
-                    connection.run_pending_migrations(server_migrations)?;
-                }
-                Ok(())
-            },
-        )
+                connection.run_pending_migrations(server_migrations)?;
+            }
+            Ok(())
+        })
         .join()
         .unwrap()
         .unwrap();
@@ -309,9 +552,60 @@ pub mod harness {
         pub async fn conn(&self) -> AsyncPgConnection {
             use diesel_async::AsyncConnection;
             let url = self.db_conf.db_url(self.db_name.as_str());
-            AsyncPgConnection::establish(&url)
+            let mut conn = AsyncPgConnection::establish(&url)
+                .await
+                .expect("Cannot establish database connection");
+            set_search_path(&mut conn)
                 .await
-                .expect("Cannot establish database connection")
+                .expect("Cannot set search_path");
+            conn
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use function_name::named;
+
+        use super::*;
+
+        #[tokio::test]
+        #[named]
+        async fn test_migrations_in_custom_schema() {
+            let db_name = to_pg_db_name(function_name!());
+            let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+            let url = harness.db_conf.db_url(&harness.db_name);
+            let applied = std::thread::spawn(move || {
+                let mut connection =
+                    AsyncConnectionWrapper::<AsyncPgConnection>::establish(&url).unwrap();
+                run_migrations_in_schema(&mut connection, "tenant_auth").unwrap()
+            })
+            .join()
+            .unwrap();
+            let expected =
+                <EmbeddedMigrations as MigrationSource<Pg>>::migrations(&MIGRATIONS).unwrap();
+            assert_eq!(applied.len(), expected.len());
+
+            #[derive(QueryableByName)]
+            struct Table {
+                #[diesel(sql_type = diesel::sql_types::Text)]
+                schemaname: String,
+            }
+            let mut conn = harness.conn().await;
+            let schemas =
+                sql_query("SELECT schemaname FROM pg_tables WHERE tablename = 'portraits'")
+                    .load::<Table>(&mut conn)
+                    .await
+                    .unwrap();
+            let mut schemas: Vec<_> = schemas.into_iter().map(|t| t.schemaname).collect();
+            schemas.sort();
+            assert_eq!(schemas, vec!["auth", "tenant_auth"]);
+            assert_eq!(
+                requalify(
+                    "CREATE SCHEMA IF NOT EXISTS auth;\nDROP TABLE auth.users;",
+                    "tenant"
+                ),
+                "\nDROP TABLE \"tenant\".users;"
+            );
         }
     }
 }
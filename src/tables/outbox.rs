@@ -0,0 +1,380 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+use crate::email::{EmailTemplate, ScheduledEmail};
+use crate::schema::auth::email_outbox;
+
+pub const OUTBOX_PENDING: &str = "pending";
+/// Handed to the scheduler and waiting for its delivery report.
+pub const OUTBOX_SENDING: &str = "sending";
+pub const OUTBOX_SENT: &str = "sent";
+pub const OUTBOX_FAILED: &str = "failed";
+pub const OUTBOX_CANCELLED: &str = "cancelled";
+
+/// A scheduled email stored durably until its delivery is reported.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = email_outbox)]
+pub struct OutboxEmail {
+    pub id: Uuid,
+    pub recipient: String,
+    pub template: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
+    pub headers: serde_json::Value,
+    pub transactional: bool,
+    /// Held until this time, if set.
+    pub send_at: Option<NaiveDateTime>,
+    pub cancel_key: Option<String>,
+    pub digest: bool,
+}
+
+impl OutboxEmail {
+    /// Write `email` to the outbox. Call this with the connection of the transaction that
+    /// produced the email so it is only sent if that transaction commits.
+    pub async fn enqueue<T>(
+        conn: &mut AsyncPgConnection,
+        email: &ScheduledEmail<T>,
+    ) -> anyhow::Result<Self>
+    where
+        T: EmailTemplate + Serialize + 'static,
+    {
+        let now = chrono::Utc::now().naive_utc();
+        let row = Self {
+            id: Uuid::new_v4(),
+            recipient: email.to.to_string(),
+            template: serde_json::to_value(&email.template)?,
+            status: OUTBOX_PENDING.to_string(),
+            attempts: 0,
+            last_error: None,
+            created: now,
+            updated: now,
+            headers: serde_json::to_value(&email.headers)?,
+            transactional: email.transactional,
+            send_at: email.send_at.map(|send_at| send_at.naive_utc()),
+            cancel_key: email.cancel_key.clone(),
+            digest: email.digest,
+        };
+        diesel::insert_into(email_outbox::table)
+            .values(&row)
+            .execute(conn)
+            .await?;
+        Ok(row)
+    }
+
+    /// Lock up to `batch_size` pending emails which are due, oldest first, along with emails
+    /// which have been sending for longer than `ack_timeout` without a delivery report, or past
+    /// the time they were held until by `hold`. Rows locked by another dispatcher are skipped,
+    /// so this must be called inside a transaction which then marks the rows.
+    pub async fn claim(
+        conn: &mut AsyncPgConnection,
+        batch_size: i64,
+        ack_timeout: Duration,
+    ) -> QueryResult<Vec<Self>> {
+        let now = chrono::Utc::now().naive_utc();
+        let stale = chrono::Duration::from_std(ack_timeout)
+            .ok()
+            .and_then(|timeout| now.checked_sub_signed(timeout))
+            .unwrap_or(NaiveDateTime::MIN);
+        email_outbox::table
+            .filter(
+                email_outbox::status
+                    .eq(OUTBOX_PENDING)
+                    .and(
+                        email_outbox::send_at
+                            .is_null()
+                            .or(email_outbox::send_at.le(now)),
+                    )
+                    .or(email_outbox::status
+                        .eq(OUTBOX_SENDING)
+                        .and(email_outbox::updated.lt(stale))
+                        .and(
+                            email_outbox::send_at
+                                .is_null()
+                                .or(email_outbox::send_at.lt(stale)),
+                        )),
+            )
+            .order(email_outbox::created.asc())
+            .limit(batch_size)
+            .for_update()
+            .skip_locked()
+            .load::<Self>(conn)
+            .await
+    }
+
+    /// Record that the emails were handed to the scheduler, counting an attempt for each.
+    pub async fn mark_sending(conn: &mut AsyncPgConnection, ids: &[Uuid]) -> QueryResult<usize> {
+        diesel::update(email_outbox::table.filter(email_outbox::id.eq_any(ids)))
+            .set((
+                email_outbox::status.eq(OUTBOX_SENDING),
+                email_outbox::attempts.eq(email_outbox::attempts + 1),
+                email_outbox::updated.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .await
+    }
+
+    /// Keep emails in flight from being claimed again until `ack_timeout` after `until`, for
+    /// stages which hold an email for longer than that, such as `digest::digest_emails`.
+    pub async fn hold(
+        conn: &mut AsyncPgConnection,
+        ids: &[Uuid],
+        until: NaiveDateTime,
+    ) -> QueryResult<usize> {
+        diesel::update(
+            email_outbox::table
+                .filter(email_outbox::id.eq_any(ids))
+                .filter(email_outbox::status.eq(OUTBOX_SENDING)),
+        )
+        .set((
+            email_outbox::send_at.eq(until),
+            email_outbox::updated.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .await
+    }
+
+    /// Cancel emails in flight which won't be sent, such as notifications the recipient opted
+    /// out of.
+    pub async fn cancel_sending(conn: &mut AsyncPgConnection, ids: &[Uuid]) -> QueryResult<usize> {
+        diesel::update(
+            email_outbox::table
+                .filter(email_outbox::id.eq_any(ids))
+                .filter(email_outbox::status.eq(OUTBOX_SENDING)),
+        )
+        .set((
+            email_outbox::status.eq(OUTBOX_CANCELLED),
+            email_outbox::updated.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .await
+    }
+
+    /// Record the delivery report of an email in flight. An email which failed is sent again
+    /// until it has been attempted `max_attempts` times, after which it is marked failed. A
+    /// `permanent` failure is marked failed straight away.
+    pub async fn acknowledge(
+        conn: &mut AsyncPgConnection,
+        id: Uuid,
+        error: Option<&str>,
+        permanent: bool,
+        max_attempts: i32,
+    ) -> QueryResult<usize> {
+        let Some(row) = email_outbox::table
+            .find(id)
+            .first::<Self>(conn)
+            .await
+            .optional()?
+        else {
+            return Ok(0);
+        };
+        let status = match error {
+            None => OUTBOX_SENT,
+            Some(_) if permanent || row.attempts >= max_attempts => OUTBOX_FAILED,
+            Some(_) => OUTBOX_PENDING,
+        };
+        diesel::update(
+            email_outbox::table
+                .find(id)
+                .filter(email_outbox::status.eq(OUTBOX_SENDING)),
+        )
+        .set((
+            email_outbox::status.eq(status),
+            email_outbox::last_error.eq(error.or(row.last_error.as_deref())),
+            email_outbox::updated.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .await
+    }
+
+    /// Cancel the pending emails enqueued with `cancel_key`. Returns how many were cancelled.
+    pub async fn cancel(conn: &mut AsyncPgConnection, cancel_key: &str) -> QueryResult<usize> {
+        diesel::update(
+            email_outbox::table
+                .filter(email_outbox::status.eq(OUTBOX_PENDING))
+                .filter(email_outbox::cancel_key.eq(cancel_key)),
+        )
+        .set((
+            email_outbox::status.eq(OUTBOX_CANCELLED),
+            email_outbox::updated.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .await
+    }
+
+    /// Record a failed attempt. The email stays pending for another try until `max_attempts`
+    /// is reached, after which it is marked failed.
+    pub async fn mark_failed(
+        &self,
+        conn: &mut AsyncPgConnection,
+        error: &str,
+        max_attempts: i32,
+    ) -> QueryResult<usize> {
+        let attempts = self.attempts + 1;
+        let status = if attempts >= max_attempts {
+            OUTBOX_FAILED
+        } else {
+            OUTBOX_PENDING
+        };
+        diesel::update(email_outbox::table.find(self.id))
+            .set((
+                email_outbox::status.eq(status),
+                email_outbox::attempts.eq(attempts),
+                email_outbox::last_error.eq(error),
+                email_outbox::updated.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use diesel_async::scoped_futures::ScopedFutureExt;
+    use diesel_async::AsyncConnection;
+    use email_address::EmailAddress;
+    use function_name::named;
+    use handlebars::Handlebars;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::email::headers::EmailHeaders;
+    use crate::email::FilledTemplate;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+
+    const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct TestTemplate {
+        link: String,
+    }
+
+    impl EmailTemplate for TestTemplate {
+        fn subject(&self) -> String {
+            "Test".to_string()
+        }
+
+        fn fill(self, _handlebars: &Handlebars) -> anyhow::Result<FilledTemplate> {
+            Ok(FilledTemplate(self.link))
+        }
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_outbox_claim() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+
+        let email = ScheduledEmail::new(
+            EmailAddress::from_str("test@example.com").expect("valid email"),
+            TestTemplate {
+                link: "https://localhost/".to_string(),
+            },
+        )
+        .with_headers(
+            EmailHeaders::default()
+                .reply_to(EmailAddress::from_str("support@example.com").unwrap()),
+        );
+        let queued = OutboxEmail::enqueue(&mut conn, &email)
+            .await
+            .expect("enqueued");
+
+        let mut other_conn = harness.conn().await;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                let claimed = OutboxEmail::claim(conn, 10, ACK_TIMEOUT).await?;
+                // Postgres truncates timestamps to microseconds, so compare ids.
+                let claimed_ids: Vec<Uuid> = claimed.iter().map(|email| email.id).collect();
+                assert_eq!(claimed_ids, vec![queued.id]);
+                let headers: EmailHeaders =
+                    serde_json::from_value(claimed[0].headers.clone()).expect("headers");
+                assert_eq!(headers, email.headers);
+
+                // A second dispatcher skips the locked row.
+                let skipped = OutboxEmail::claim(&mut other_conn, 10, ACK_TIMEOUT).await?;
+                assert!(skipped.is_empty());
+
+                OutboxEmail::mark_sending(conn, &[queued.id]).await?;
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await
+        .expect("transaction");
+
+        let remaining = OutboxEmail::claim(&mut conn, 10, ACK_TIMEOUT)
+            .await
+            .expect("claim");
+        assert!(remaining.is_empty());
+
+        // Emails in flight are claimed again if their delivery isn't reported in time.
+        let reclaimed = OutboxEmail::claim(&mut conn, 10, Duration::ZERO)
+            .await
+            .expect("claim");
+        assert_eq!(reclaimed[0].status, OUTBOX_SENDING);
+        assert_eq!(reclaimed[0].attempts, 1);
+        let acknowledged = OutboxEmail::acknowledge(&mut conn, queued.id, None, false, 5)
+            .await
+            .expect("acknowledged");
+        assert_eq!(acknowledged, 1);
+        let remaining = OutboxEmail::claim(&mut conn, 10, Duration::ZERO)
+            .await
+            .expect("claim");
+        assert!(remaining.is_empty());
+
+        // Delayed emails are only claimed once due, and can be cancelled until then.
+        let now = chrono::Utc::now();
+        let delayed = || {
+            ScheduledEmail::new(
+                EmailAddress::from_str("test@example.com").expect("valid email"),
+                TestTemplate {
+                    link: "https://localhost/".to_string(),
+                },
+            )
+        };
+        let later = delayed()
+            .send_at(now + chrono::Duration::hours(1))
+            .cancel_key("reminder:test");
+        OutboxEmail::enqueue(&mut conn, &later)
+            .await
+            .expect("enqueued");
+        let due = delayed().send_at(now - chrono::Duration::seconds(1));
+        let due = OutboxEmail::enqueue(&mut conn, &due)
+            .await
+            .expect("enqueued");
+        let claimed = OutboxEmail::claim(&mut conn, 10, ACK_TIMEOUT)
+            .await
+            .expect("claim");
+        let claimed_ids: Vec<Uuid> = claimed.iter().map(|email| email.id).collect();
+        assert_eq!(claimed_ids, vec![due.id]);
+
+        // A permanent failure isn't sent again, however many attempts are left.
+        OutboxEmail::mark_sending(&mut conn, &[due.id])
+            .await
+            .expect("sending");
+        OutboxEmail::acknowledge(&mut conn, due.id, Some("550 No such user"), true, 5)
+            .await
+            .expect("acknowledged");
+        let remaining = OutboxEmail::claim(&mut conn, 10, Duration::ZERO)
+            .await
+            .expect("claim");
+        assert!(remaining.is_empty());
+        let cancelled = OutboxEmail::cancel(&mut conn, "reminder:test")
+            .await
+            .expect("cancelled");
+        assert_eq!(cancelled, 1);
+        let cancelled = OutboxEmail::cancel(&mut conn, "reminder:test")
+            .await
+            .expect("cancelled");
+        assert_eq!(cancelled, 0);
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use email_address::EmailAddress;
+
+use crate::schema::auth::email_suppressions;
+
+/// Why an address no longer receives email.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuppressionReason {
+    /// The mailbox doesn't exist or permanently refuses mail.
+    HardBounce,
+    /// The recipient marked an email as spam.
+    Complaint,
+    /// Added by an operator or an unsubscribe.
+    Manual,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HardBounce => "hard-bounce",
+            Self::Complaint => "complaint",
+            Self::Manual => "manual",
+        }
+    }
+}
+
+impl fmt::Display for SuppressionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SuppressionReason {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hard-bounce" => Ok(Self::HardBounce),
+            "complaint" => Ok(Self::Complaint),
+            "manual" => Ok(Self::Manual),
+            _ => anyhow::bail!("Unknown suppression reason: {}", s),
+        }
+    }
+}
+
+/// An address which is no longer sent non-transactional email.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = email_suppressions)]
+pub struct Suppression {
+    pub email: String,
+    pub reason: String,
+    pub detail: Option<String>,
+    pub created: NaiveDateTime,
+}
+
+/// Addresses are compared case-insensitively.
+fn normalize(email: &EmailAddress) -> String {
+    email.as_str().to_lowercase()
+}
+
+impl Suppression {
+    /// Suppress `email`, replacing the reason of an existing suppression.
+    pub async fn add(
+        conn: &mut AsyncPgConnection,
+        email: &EmailAddress,
+        reason: SuppressionReason,
+        detail: Option<&str>,
+    ) -> QueryResult<Self> {
+        let row = Self {
+            email: normalize(email),
+            reason: reason.to_string(),
+            detail: detail.map(str::to_string),
+            created: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(email_suppressions::table)
+            .values(&row)
+            .on_conflict(email_suppressions::email)
+            .do_update()
+            .set((
+                email_suppressions::reason.eq(excluded(email_suppressions::reason)),
+                email_suppressions::detail.eq(excluded(email_suppressions::detail)),
+            ))
+            .get_result(conn)
+            .await
+    }
+
+    pub async fn get(
+        conn: &mut AsyncPgConnection,
+        email: &EmailAddress,
+    ) -> QueryResult<Option<Self>> {
+        email_suppressions::table
+            .find(normalize(email))
+            .first::<Self>(conn)
+            .await
+            .optional()
+    }
+
+    pub async fn is_suppressed(
+        conn: &mut AsyncPgConnection,
+        email: &EmailAddress,
+    ) -> QueryResult<bool> {
+        Ok(Self::get(conn, email).await?.is_some())
+    }
+
+    /// Allow email to `email` again. Returns whether it was suppressed.
+    pub async fn remove(conn: &mut AsyncPgConnection, email: &EmailAddress) -> QueryResult<bool> {
+        let deleted = diesel::delete(email_suppressions::table.find(normalize(email)))
+            .execute(conn)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    pub fn reason(&self) -> anyhow::Result<SuppressionReason> {
+        self.reason.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use function_name::named;
+
+    use super::*;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[tokio::test]
+    #[named]
+    async fn test_suppressions() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+
+        let email = EmailAddress::from_str("Bounce@Example.com").expect("valid email");
+        let lower = EmailAddress::from_str("bounce@example.com").expect("valid email");
+        assert!(!Suppression::is_suppressed(&mut conn, &email)
+            .await
+            .expect("query"));
+
+        Suppression::add(
+            &mut conn,
+            &email,
+            SuppressionReason::HardBounce,
+            Some("550"),
+        )
+        .await
+        .expect("added");
+        let row = Suppression::add(&mut conn, &lower, SuppressionReason::Complaint, None)
+            .await
+            .expect("updated");
+        assert_eq!(row.reason().expect("reason"), SuppressionReason::Complaint);
+        assert!(Suppression::is_suppressed(&mut conn, &lower)
+            .await
+            .expect("query"));
+
+        assert!(Suppression::remove(&mut conn, &email)
+            .await
+            .expect("removed"));
+        assert!(!Suppression::is_suppressed(&mut conn, &email)
+            .await
+            .expect("query"));
+    }
+}
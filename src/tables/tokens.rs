@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use email_address::EmailAddress;
+
+use super::{gen_rand_string, hash_token, UserId};
+use crate::schema::auth::one_time_tokens;
+
+/// What a one-time token allows its holder to do. A token is only ever accepted for the purpose
+/// it was issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenPurpose {
+    Verify,
+    Reset,
+    Invite,
+    EmailChange,
+    Login,
+}
+
+static TOKEN_TTLS: OnceLock<RwLock<HashMap<TokenPurpose, Duration>>> = OnceLock::new();
+
+fn token_ttls() -> &'static RwLock<HashMap<TokenPurpose, Duration>> {
+    TOKEN_TTLS.get_or_init(Default::default)
+}
+
+/// Override how long newly issued tokens for `purpose` are valid.
+pub fn set_token_ttl(purpose: TokenPurpose, ttl: Duration) {
+    token_ttls().write().unwrap().insert(purpose, ttl);
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Verify => "verify",
+            Self::Reset => "reset",
+            Self::Invite => "invite",
+            Self::EmailChange => "email-change",
+            Self::Login => "login",
+        }
+    }
+
+    pub fn default_ttl(&self) -> Duration {
+        match self {
+            Self::Verify => Duration::hours(24),
+            Self::Reset => Duration::hours(1),
+            Self::Invite => Duration::days(7),
+            Self::EmailChange => Duration::hours(1),
+            Self::Login => Duration::minutes(15),
+        }
+    }
+
+    /// The lifetime set with `set_token_ttl`, if any.
+    pub fn configured_ttl(&self) -> Option<Duration> {
+        token_ttls().read().unwrap().get(self).copied()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.configured_ttl().unwrap_or_else(|| self.default_ttl())
+    }
+}
+
+impl fmt::Display for TokenPurpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TokenPurpose {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "verify" => Self::Verify,
+            "reset" => Self::Reset,
+            "invite" => Self::Invite,
+            "email-change" => Self::EmailChange,
+            "login" => Self::Login,
+            _ => anyhow::bail!("Unknown token purpose: {}", s),
+        })
+    }
+}
+
+/// A single-use token. The raw token is handed to the user and only its hash is stored.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = one_time_tokens)]
+pub struct OneTimeToken {
+    pub id: String,
+    pub email: String,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub code_hash: Option<String>,
+    pub failed_attempts: i32,
+    pub purpose: String,
+    pub user_id: Option<UserId>,
+    pub payload: Option<serde_json::Value>,
+}
+
+/// Builds a one-time token. Created with `OneTimeToken::builder`.
+pub struct IssueToken {
+    purpose: TokenPurpose,
+    email: EmailAddress,
+    user_id: Option<UserId>,
+    payload: Option<serde_json::Value>,
+    ttl: Duration,
+}
+
+impl IssueToken {
+    pub fn user_id(mut self, user_id: UserId) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    /// Data to hand back when the token is consumed, such as the new address for an email change.
+    pub fn payload(mut self, payload: serde_json::Value) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Store the token, returning the raw token to send to the user.
+    pub async fn issue(self, conn: &mut AsyncPgConnection) -> QueryResult<String> {
+        let now = chrono::Utc::now().naive_utc();
+        let token = gen_rand_string(32);
+        let row = OneTimeToken {
+            id: hash_token(&token),
+            email: self.email.to_string(),
+            created: now,
+            expires: now + self.ttl,
+            code_hash: None,
+            failed_attempts: 0,
+            purpose: self.purpose.as_str().to_string(),
+            user_id: self.user_id,
+            payload: self.payload,
+        };
+        diesel::insert_into(one_time_tokens::table)
+            .values(&row)
+            .execute(conn)
+            .await?;
+        Ok(token)
+    }
+}
+
+impl OneTimeToken {
+    pub fn builder(purpose: TokenPurpose, email: &EmailAddress) -> IssueToken {
+        IssueToken {
+            purpose,
+            email: email.clone(),
+            user_id: None,
+            payload: None,
+            ttl: purpose.ttl(),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        chrono::Utc::now().naive_utc() <= self.expires
+    }
+
+    /// Delete the token and return it if it was issued for `purpose` and hasn't expired. The
+    /// delete is what claims the token, so two requests can never both consume it.
+    pub async fn consume(
+        conn: &mut AsyncPgConnection,
+        purpose: TokenPurpose,
+        token: &str,
+    ) -> QueryResult<Option<Self>> {
+        let row: Option<Self> = diesel::delete(
+            one_time_tokens::table
+                .filter(one_time_tokens::id.eq(hash_token(token)))
+                .filter(one_time_tokens::purpose.eq(purpose.as_str())),
+        )
+        .get_result(conn)
+        .await
+        .optional()?;
+        Ok(row.filter(Self::is_valid))
+    }
+
+    /// Delete up to `batch_size` expired tokens of any purpose, returning how many were deleted.
+    pub async fn purge_expired(
+        conn: &mut AsyncPgConnection,
+        batch_size: i64,
+    ) -> QueryResult<usize> {
+        let now = chrono::Utc::now().naive_utc();
+        let expired: Vec<String> = one_time_tokens::table
+            .select(one_time_tokens::id)
+            .filter(one_time_tokens::expires.lt(now))
+            .limit(batch_size)
+            .load(conn)
+            .await?;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        diesel::delete(one_time_tokens::table.filter(one_time_tokens::id.eq_any(expired)))
+            .execute(conn)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use function_name::named;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[tokio::test]
+    #[named]
+    async fn test_one_time_token() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, None).await;
+        let mut conn = harness.conn().await;
+
+        let email = EmailAddress::from_str("test@example.com").expect("valid email");
+        let user_id = UserId(Uuid::new_v4());
+        let token = OneTimeToken::builder(TokenPurpose::EmailChange, &email)
+            .user_id(user_id)
+            .payload(json!({"email": "new@example.com"}))
+            .issue(&mut conn)
+            .await
+            .expect("issued");
+
+        let wrong_purpose = OneTimeToken::consume(&mut conn, TokenPurpose::Reset, &token)
+            .await
+            .expect("consumed");
+        assert!(wrong_purpose.is_none());
+
+        let consumed = OneTimeToken::consume(&mut conn, TokenPurpose::EmailChange, &token)
+            .await
+            .expect("consumed")
+            .expect("valid token");
+        assert_eq!(consumed.user_id, Some(user_id));
+        assert_eq!(consumed.payload, Some(json!({"email": "new@example.com"})));
+
+        let reused = OneTimeToken::consume(&mut conn, TokenPurpose::EmailChange, &token)
+            .await
+            .expect("consumed");
+        assert!(reused.is_none());
+
+        let expired = OneTimeToken::builder(TokenPurpose::Invite, &email)
+            .ttl(Duration::minutes(-1))
+            .issue(&mut conn)
+            .await
+            .expect("issued");
+        let consumed = OneTimeToken::consume(&mut conn, TokenPurpose::Invite, &expired)
+            .await
+            .expect("consumed");
+        assert!(consumed.is_none());
+    }
+}